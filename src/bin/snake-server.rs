@@ -0,0 +1,97 @@
+use anyhow::Context;
+use rendering::{
+    config::GameConfig,
+    level::Level,
+    net::{DEFAULT_PORT, DEFAULT_WEBSOCKET_PORT},
+    rollback::Netcode,
+    server::Server,
+};
+
+const USAGE: &str = "Usage: snake-server [options]
+
+Options:
+    --bind <address>        Address to listen on, 127.0.0.1:7878 by default
+    --ws-bind <address>     Address to take browser players on over WebSockets,
+                            127.0.0.1:7879 by default
+    --config <file.json>    Load game settings from a JSON file
+    --level <name|file>     Play a built-in level or a level file
+    --players <n>           Players needed before a game starts
+    --lockstep              Have every player simulate the game, passing only
+                            turns around, rather than streaming snapshots
+    --input-delay <ticks>   Ticks before a turn takes effect in lockstep, 2 by default
+    --max-prediction <n>    Ticks players may guess ahead of missing turns in
+                            lockstep, 8 by default, 0 to always wait";
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> anyhow::Result<T> {
+    value.parse().map_err(|_| anyhow::anyhow!("{flag} expects a number, got {value}"))
+}
+
+/// Reads the config at `path`, for two players unless it says otherwise.
+/// Validated once the other flags have been applied.
+fn load_config(path: &str) -> anyhow::Result<GameConfig> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Couldn't read config from {path}"))?;
+    let mut fields: serde_json::Value = serde_json::from_str(&json).with_context(|| format!("Config {path} is not valid JSON"))?;
+    if let Some(fields) = fields.as_object_mut() {
+        fields.entry("players").or_insert(2.into());
+    }
+    serde_json::from_value(fields).with_context(|| format!("Couldn't load config {path}"))
+}
+
+struct Args {
+    address: String,
+    websocket_address: String,
+    config: GameConfig,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut address = format!("127.0.0.1:{DEFAULT_PORT}");
+    let mut websocket_address = format!("127.0.0.1:{DEFAULT_WEBSOCKET_PORT}");
+    let args = args.collect::<Vec<_>>();
+    // Loaded first so the other flags can override it wherever they appear
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).ok_or_else(|| anyhow::anyhow!("--config needs a file\n{USAGE}"))?;
+            load_config(path)?
+        },
+        None => GameConfig { players: 2, ..GameConfig::default() },
+    };
+    let mut level = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        if arg == "--lockstep" {
+            config.lockstep.get_or_insert_with(Netcode::default);
+            continue;
+        }
+        let value = args.next().ok_or_else(|| anyhow::anyhow!("{arg} needs a value\n{USAGE}"))?;
+        match arg.as_str() {
+            "--bind" => address = value,
+            "--ws-bind" => websocket_address = value,
+            "--config" => {},
+            "--level" => level = Some(Level::find(&value)?),
+            "--players" => config.players = parse_number(&arg, &value)?,
+            "--input-delay" => config.lockstep.get_or_insert_with(Netcode::default).input_delay = parse_number(&arg, &value)?,
+            "--max-prediction" => {
+                config.lockstep.get_or_insert_with(Netcode::default).max_prediction = parse_number(&arg, &value)?;
+            },
+            _ => anyhow::bail!("Unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    if let Some(level) = level {
+        config.set_level(&level);
+    }
+    config.validate()?;
+    Ok(Args { address, websocket_address, config })
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = parse_args(std::env::args().skip(1))?;
+    let mut server = Server::bind(args.address, args.config)?;
+    server.serve_websockets(args.websocket_address)?;
+    server.run()
+}
@@ -0,0 +1,347 @@
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Vector3};
+use wgpu::util::DeviceExt;
+
+use crate::{
+    model::{self, ModelPaths},
+    snake::{Board, DrawModel, Instance, InstanceRaw, Mesh, Model, ModelVertex, Sprite, Vertex, ATLAS_TILE_SIZE},
+    texture,
+};
+
+/// Texels with less alpha than this in the atlas are left out of the meshes
+const OPAQUE: u8 = 128;
+
+/// How tall each sprite is extruded, in cells
+fn extrusion(sprite: Sprite) -> f32 {
+    match sprite {
+        // A little taller and shorter than the body, so the ends sliding
+        // over it between ticks don't fight it for the same depth
+        Sprite::Head => 0.45,
+        Sprite::Tail => 0.35,
+        Sprite::Body | Sprite::Corner => 0.4,
+        Sprite::Wall => 1.0,
+        Sprite::Obstacle => 0.6,
+        // Round instead, see `sphere`
+        Sprite::Apple => 0.0,
+    }
+}
+
+/// Draws the board in 3D: sprites extruded into blocks of texels, a round
+/// apple and a ground plane, lit from one side. Meant for a tilted camera.
+pub struct Board3d {
+    pipeline: wgpu::RenderPipeline,
+    ground_pipeline: wgpu::RenderPipeline,
+    pub depth_texture: texture::Texture,
+    /// One per sprite, in `Sprite::ALL` order
+    meshes: Vec<Mesh>,
+    /// Drawn instead of the mesh in the same place, see `load_models`
+    models: Vec<Option<Model>>,
+    ground: Mesh,
+    /// What `ground` was built for: board, cell size and ghost cells
+    ground_for: Option<(Board, f32, i32)>,
+    /// The ground's one instance, where its mesh already is
+    ground_instance: wgpu::Buffer,
+    /// Every instance this frame, grouped by mesh
+    instance_buffer: wgpu::Buffer,
+    batches: Vec<(usize, Range<u32>)>,
+}
+
+impl Board3d {
+    /// `atlas` is the sprite atlas the meshes are shaped after, `layout` and
+    /// `shader` the ones the flat pipeline uses
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        layout: &wgpu::PipelineLayout,
+        shader: &wgpu::ShaderModule,
+        atlas: &image::RgbaImage,
+        cell_size: f32,
+    ) -> Self {
+        let pipeline = create_pipeline(device, config.format, layout, shader, "fs_mesh");
+        let ground_pipeline = create_pipeline(device, config.format, layout, shader, "fs_ground");
+        let meshes = Sprite::ALL
+            .iter()
+            .map(|&sprite| {
+                let (vertices, indices) = match sprite {
+                    Sprite::Apple => sphere(cell_size * 0.4, 16, 8),
+                    _ => extrude(atlas, sprite, cell_size, extrusion(sprite) * cell_size),
+                };
+                Mesh::new(device, &format!("{sprite:?}"), &vertices, &indices)
+            })
+            .collect();
+        let (vertices, indices) = ground(&Board::new(1, 1), cell_size, 0);
+        Self {
+            pipeline,
+            ground_pipeline,
+            depth_texture: texture::Texture::create_depth_texture(device, config, "Depth Texture"),
+            meshes,
+            models: Sprite::ALL.iter().map(|_| None).collect(),
+            ground: Mesh::new(device, "Ground", &vertices, &indices),
+            ground_for: None,
+            ground_instance: create_instance_buffer(device, &[identity()]),
+            instance_buffer: create_instance_buffer(device, &[identity()]),
+            batches: Vec::new(),
+        }
+    }
+
+    /// Swaps in the models in `paths` for the sprites they're for, keeping
+    /// the built-in mesh for any that can't be loaded
+    pub fn load_models(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        paths: &ModelPaths,
+        cell_size: f32,
+    ) {
+        for (slot, &sprite) in self.models.iter_mut().zip(&Sprite::ALL) {
+            *slot = paths.get(sprite).and_then(|path| {
+                model::load(device, queue, layout, path, cell_size).map_err(|e| log::error!("{e:#}")).ok()
+            });
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        self.depth_texture = texture::Texture::create_depth_texture(device, config, "Depth Texture");
+    }
+
+    /// Uploads this frame's instances, grouped so each mesh is drawn once.
+    /// The ground covers the board and `ghost_cells` past its edges.
+    pub fn prepare<'a>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: impl Iterator<Item = &'a Instance>,
+        board: &Board,
+        cell_size: f32,
+        ghost_cells: i32,
+    ) {
+        let ground_for = Some((*board, cell_size, ghost_cells));
+        if self.ground_for != ground_for {
+            let (vertices, indices) = ground(board, cell_size, ghost_cells);
+            self.ground = Mesh::new(device, "Ground", &vertices, &indices);
+            self.ground_for = ground_for;
+        }
+
+        let mut grouped = vec![Vec::new(); Sprite::ALL.len()];
+        for instance in instances {
+            let mesh = Sprite::ALL.iter().position(|&sprite| sprite == instance.sprite).unwrap_or(0);
+            let mut instance = *instance;
+            if self.models[mesh].is_some() {
+                // Models bring their own textures rather than a tile of the atlas
+                instance.atlas_rect = [0.0, 0.0, 1.0, 1.0];
+            } else if instance.sprite == Sprite::Apple {
+                // The built-in apple is drawn from its centre, half a cell up
+                instance.position.z += cell_size * 0.4;
+            }
+            grouped[mesh].push(instance.to_raw());
+        }
+        self.batches.clear();
+        let mut data = Vec::new();
+        for (mesh, raws) in grouped.into_iter().enumerate() {
+            if raws.is_empty() {
+                continue;
+            }
+            let start = data.len() as u32;
+            data.extend(raws);
+            self.batches.push((mesh, start..data.len() as u32));
+        }
+        if self.instance_buffer.size() < (data.len() * std::mem::size_of::<InstanceRaw>()) as u64 {
+            self.instance_buffer = create_instance_buffer(device, &data);
+        } else {
+            queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&data));
+        }
+    }
+
+    /// Draws into a pass with `depth_texture` attached
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, atlas: &'a wgpu::BindGroup, camera: &'a wgpu::BindGroup) {
+        render_pass.set_bind_group(1, camera, &[]);
+        if !self.batches.is_empty() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            for (mesh, instances) in &self.batches {
+                match &self.models[*mesh] {
+                    Some(model) => render_pass.draw_model_instanced(model, instances.clone()),
+                    None => {
+                        render_pass.set_bind_group(0, atlas, &[]);
+                        render_pass.draw_mesh_instanced(&self.meshes[*mesh], instances.clone());
+                    },
+                }
+            }
+        }
+        // Drawn last so the depth test skips whatever is hidden under the snakes
+        render_pass.set_bind_group(0, atlas, &[]);
+        render_pass.set_pipeline(&self.ground_pipeline);
+        render_pass.set_vertex_buffer(1, self.ground_instance.slice(..));
+        render_pass.draw_mesh_instanced(&self.ground, 0..1);
+    }
+}
+
+/// An instance that leaves its mesh where it is
+fn identity() -> InstanceRaw {
+    Instance {
+        sprite: Sprite::Wall,
+        position: Vector3::new(0.0, 0.0, 0.0),
+        rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+        atlas_rect: [0.0, 0.0, 1.0, 1.0],
+    }
+    .to_raw()
+}
+
+fn create_instance_buffer(device: &wgpu::Device, data: &[InstanceRaw]) -> wgpu::Buffer {
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("3D Instance Buffer"),
+        contents: bytemuck::cast_slice(data),
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    fragment: &str,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(fragment),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_mesh",
+            buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: fragment,
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+        cache: None,
+    })
+}
+
+/// Adds a quad with corners `a` to `d` in order round its edge, wound to face
+/// along `normal`
+fn push_quad(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut Vec<u32>,
+    corners: [Vector3<f32>; 4],
+    tex_coords: [[f32; 2]; 4],
+    normal: Vector3<f32>,
+) {
+    let [a, b, c, _] = corners;
+    let facing = (b - a).cross(c - a).dot(normal) > 0.0;
+    let start = vertices.len() as u32;
+    for (corner, tex_coords) in corners.into_iter().zip(tex_coords) {
+        vertices.push(ModelVertex { position: corner.into(), tex_coords, normal: normal.into() });
+    }
+    let order = if facing { [0, 1, 2, 0, 2, 3] } else { [0, 2, 1, 0, 3, 2] };
+    indices.extend(order.map(|i| start + i));
+}
+
+/// Every opaque texel of `sprite`'s tile as a column `height` tall, standing
+/// on the board in a cell of `cell_size`. Sides are only made where a column
+/// has no neighbour, and every face takes the colour of its texel.
+fn extrude(atlas: &image::RgbaImage, sprite: Sprite, cell_size: f32, height: f32) -> (Vec<ModelVertex>, Vec<u32>) {
+    let tiles = ATLAS_TILE_SIZE as i32;
+    let [u, v, _, _] = sprite.atlas_rect(0);
+    let (left, top) = ((u * atlas.width() as f32) as i32, (v * atlas.height() as f32) as i32);
+    let opaque = |x: i32, y: i32| {
+        (0..tiles).contains(&x) && (0..tiles).contains(&y) && atlas.get_pixel((left + x) as u32, (top + y) as u32)[3] >= OPAQUE
+    };
+    let texel = cell_size / tiles as f32;
+    // Texel rows count down from the top of the tile, the board's y counts up
+    let corner = |x: i32, y: i32, z: f32| Vector3::new(x as f32 * texel - cell_size / 2.0, cell_size / 2.0 - y as f32 * texel, z);
+
+    let (mut vertices, mut indices) = (Vec::new(), Vec::new());
+    for y in 0..tiles {
+        for x in (0..tiles).filter(|&x| opaque(x, y)) {
+            let tex_coords = [[(x as f32 + 0.5) / tiles as f32, (y as f32 + 0.5) / tiles as f32]; 4];
+            let top_face = [corner(x, y, height), corner(x + 1, y, height), corner(x + 1, y + 1, height), corner(x, y + 1, height)];
+            push_quad(&mut vertices, &mut indices, top_face, tex_coords, Vector3::unit_z());
+            let sides = [
+                (x - 1, y, corner(x, y, 0.0), corner(x, y + 1, 0.0), -Vector3::unit_x()),
+                (x + 1, y, corner(x + 1, y, 0.0), corner(x + 1, y + 1, 0.0), Vector3::unit_x()),
+                (x, y - 1, corner(x, y, 0.0), corner(x + 1, y, 0.0), Vector3::unit_y()),
+                (x, y + 1, corner(x, y + 1, 0.0), corner(x + 1, y + 1, 0.0), -Vector3::unit_y()),
+            ];
+            for (nx, ny, a, b, normal) in sides {
+                if !opaque(nx, ny) {
+                    let up = Vector3::unit_z() * height;
+                    push_quad(&mut vertices, &mut indices, [a, b, b + up, a + up], tex_coords, normal);
+                }
+            }
+        }
+    }
+    (vertices, indices)
+}
+
+/// A ball of `radius` round the origin, textured with the middle of its tile
+/// so none of the transparent border shows
+fn sphere(radius: f32, segments: u32, rings: u32) -> (Vec<ModelVertex>, Vec<u32>) {
+    let mut vertices = Vec::new();
+    for ring in 0..=rings {
+        let latitude = std::f32::consts::PI * ring as f32 / rings as f32;
+        for segment in 0..=segments {
+            let longitude = std::f32::consts::TAU * segment as f32 / segments as f32;
+            let normal = Vector3::new(latitude.sin() * longitude.cos(), latitude.sin() * longitude.sin(), latitude.cos());
+            vertices.push(ModelVertex {
+                position: (normal * radius).into(),
+                tex_coords: [0.3 + 0.4 * segment as f32 / segments as f32, 0.3 + 0.5 * ring as f32 / rings as f32],
+                normal: normal.normalize().into(),
+            });
+        }
+    }
+    let mut indices = Vec::new();
+    let row = segments + 1;
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let (a, b) = (ring * row + segment, (ring + 1) * row + segment);
+            indices.extend([a, b, a + 1, a + 1, b, b + 1]);
+        }
+    }
+    (vertices, indices)
+}
+
+/// A flat plane under the board and `ghost_cells` past each edge, with its
+/// texture coordinates counting cells from the board's corner
+fn ground(board: &Board, cell_size: f32, ghost_cells: i32) -> (Vec<ModelVertex>, Vec<u32>) {
+    let (half_width, half_height) = (board.width as f32 / 2.0 + ghost_cells as f32, board.height as f32 / 2.0 + ghost_cells as f32);
+    let (mut vertices, mut indices) = (Vec::new(), Vec::new());
+    let corners = [(-half_width, -half_height), (half_width, -half_height), (half_width, half_height), (-half_width, half_height)];
+    // Just under the board so it never fights with anything standing on it
+    let below = -cell_size * 0.01;
+    push_quad(
+        &mut vertices,
+        &mut indices,
+        corners.map(|(x, y)| Vector3::new(x * cell_size, y * cell_size, below)),
+        corners.map(|(x, y)| [x + board.width as f32 / 2.0, y + board.height as f32 / 2.0]),
+        Vector3::unit_z(),
+    );
+    (vertices, indices)
+}
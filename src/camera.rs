@@ -1,8 +1,9 @@
 
 
-use cgmath::{self};
+use cgmath::{self, prelude::*, Deg, Point3, Rad, Vector2, Vector3};
+use winit::event::MouseScrollDelta;
 
-use crate::math::OPENGL_TO_WGPU_MATRIX;
+use crate::{clock::Instant, math::OPENGL_TO_WGPU_MATRIX, snake::Board};
 
 
 
@@ -14,8 +15,6 @@ pub struct Camera {
     pub fovy: f32,
     pub znear: f32,
     pub zfar: f32,
-    pub pitch: f32,
-    pub yaw: f32,
 }
 
 impl Camera {
@@ -57,67 +56,316 @@ impl CameraUniform {
         self.view_proj = camera.build_view_projection_matrix().into();
     }
 
-    pub fn rotate(&mut self,camera: &Camera ,pitch: f32, _yaw: f32) {
-        self.view_proj = (camera.build_view_projection_matrix() * cgmath::Matrix4::from_angle_x(cgmath::Deg(pitch))).into();
+}
+
 
+/// How far one line of scrolling zooms in
+const ZOOM_PER_LINE: f32 = 0.9;
+/// Scrolling reported in pixels is turned into lines at this rate
+const PIXELS_PER_LINE: f64 = 40.0;
+/// Radians turned per pixel dragged
+const DRAG_SENSITIVITY: f32 = 0.005;
+/// How quickly the camera closes on where it is heading, the share left
+/// after a second is `exp(-SMOOTHING)`
+const SMOOTHING: f32 = 8.0;
+const MIN_DISTANCE: f32 = 0.3;
+/// Farthest out the camera zooms, as a multiple of the distance that fits
+/// the board
+const MAX_ZOOM_OUT: f32 = 2.0;
+/// Kept short of side on, where the board would be edge on and `up` would
+/// line up with the view
+const MAX_TILT: Deg<f32> = Deg(80.0);
+/// Room left around the board when fitting it in view
+const FIT_MARGIN: f32 = 1.04;
+/// Cells seen across the view when following the head
+const FOLLOW_CELLS: f32 = 20.0;
+/// The head can move this share of the view from the middle before the
+/// follow camera moves with it
+const DEAD_ZONE: f32 = 0.25;
+/// Boards whose cells would come out smaller than this many pixels when
+/// fitted to the window start with the follow camera
+const MIN_CELL_PIXELS: u32 = 12;
 
+/// Where the camera sits, circling the point it looks at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orbit {
+    pub target: Point3<f32>,
+    /// Turn around the board's normal, 0 keeps the top of the board up
+    pub yaw: Rad<f32>,
+    /// Angle away from looking straight down onto the board, 0 is top down
+    pub tilt: Rad<f32>,
+    /// From the target to the eye, in world units
+    pub distance: f32,
+}
+
+impl Orbit {
+    pub fn eye(&self) -> Point3<f32> {
+        let (sin_tilt, cos_tilt) = self.tilt.sin_cos();
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        self.target + Vector3::new(sin_tilt * sin_yaw, -sin_tilt * cos_yaw, cos_tilt) * self.distance
     }
 
+    /// Up on screen, towards the top of the board as turned by `yaw`
+    pub fn up(&self) -> Vector3<f32> {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        Vector3::new(-sin_yaw, cos_yaw, 0.0)
+    }
+
+    /// Moves `t` of the way to `goal`, turning whichever way round is shorter
+    fn approach(&mut self, goal: &Orbit, t: f32) {
+        self.target += (goal.target - self.target) * t;
+        let turn = (goal.yaw - self.yaw).normalize_signed();
+        self.yaw = (self.yaw + turn * t).normalize();
+        self.tilt += (goal.tilt - self.tilt) * t;
+        self.distance += (goal.distance - self.distance) * t;
+    }
+
+    fn clamp(&mut self, max_distance: f32) {
+        self.tilt = Rad(self.tilt.0.clamp(0.0, Rad::from(MAX_TILT).0));
+        self.distance = self.distance.clamp(MIN_DISTANCE, max_distance.max(MIN_DISTANCE));
+    }
 }
 
- 
-pub struct CameraStaging {
-    pub camera: Camera,
-    pub model_rotation: cgmath::Deg<f32>,
+/// The board as the camera sees it, centred on the origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    /// Half the board's width and height in world units
+    pub half_size: Vector2<f32>,
+    pub cell_size: f32,
+    /// Snakes wrap round the edges, so there is no edge to stop at
+    pub wraps: bool,
 }
 
-impl CameraStaging {
-    pub fn new(camera: Camera) -> Self {
-        Self {
-            camera,
-            model_rotation: cgmath::Deg(0.0),
+/// Views the camera can be switched between, C cycles through them
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum CameraPreset {
+    /// The whole board seen from straight above
+    #[default]
+    TopDown,
+    /// The whole board seen at an angle from below
+    ThreeQuarter,
+    /// Close above the head, tilted to look ahead of it, moving only once
+    /// the head nears the edge of the view
+    Follow,
+}
+
+impl CameraPreset {
+    pub fn next(self) -> Self {
+        match self {
+            CameraPreset::TopDown => CameraPreset::ThreeQuarter,
+            CameraPreset::ThreeQuarter => CameraPreset::Follow,
+            CameraPreset::Follow => CameraPreset::TopDown,
         }
     }
-    pub fn update_camera(&mut self, camera_uniform: &mut CameraUniform) {
-        
 
-        camera_uniform.view_proj = (self.camera.build_view_projection_matrix()
-        ).into();
+    /// Top down if the whole board fits legibly in `window_pixels`,
+    /// otherwise following the head
+    pub fn for_board(board: &Board, window_pixels: u32) -> Self {
+        let cells = board.width.max(board.height).max(1) as u32;
+        if cells * MIN_CELL_PIXELS > window_pixels {
+            CameraPreset::Follow
+        } else {
+            CameraPreset::TopDown
+        }
     }
+}
 
-    pub fn update_camera_pitch(&mut self, camera_uniform: &mut CameraUniform) {
-        let v = self.camera.target - self.camera.eye;
-        let pitch = self.camera.pitch;
-        eprintln!("Sine of pitch: {}", pitch.sin());
-        let new_p = cgmath::Point3::new(
-            self.camera.target.x,
-            v.y * pitch.cos() - v.z * pitch.sin(),
-            v.y * pitch.sin() + v.z * pitch.cos()
-        );
-        
-        //eprintln!("camera target: {:?}", new_p.to_vec());
-        self.camera.target = new_p;
-        self.camera.pitch = 0.0;
-        camera_uniform.view_proj = (self.camera.build_view_projection_matrix()
-        ).into();
-    }
-
-    pub fn update_camera_yaw(&mut self, camera_uniform: &mut CameraUniform) {
-        let v = self.camera.target - self.camera.eye;
-        let pitch = self.camera.yaw;
-        eprintln!("Sine of pitch: {}", pitch.sin());
-        let new_p = cgmath::Point3::new(
-            v.x * pitch.cos() + v.z * pitch.cos(),
-            v.y,
-            -v.x * pitch.cos() + v.z * pitch.cos()
-        );
-        
-        //eprintln!("camera target: {:?}", new_p.to_vec());
-        self.camera.target = new_p;
-        self.camera.yaw = 0.0;
-        camera_uniform.view_proj = (self.camera.build_view_projection_matrix()
-        ).into();
+/// Moves the camera around the board: dragging with the left mouse button
+/// orbits and tilts, scrolling zooms and presets are eased into.
+pub struct CameraController {
+    pub preset: CameraPreset,
+    /// Where the camera is this frame
+    pub orbit: Orbit,
+    /// Where it is easing towards
+    pub goal: Orbit,
+    pub bounds: Bounds,
+    /// Half the width and height in view at a distance of one, top down
+    view: Vector2<f32>,
+    dragging: bool,
+    cursor: Option<(f64, f64)>,
+    last_update: Instant,
+}
+
+/// Half the width and height `camera` sees at a distance of one
+fn view_of(camera: &Camera) -> Vector2<f32> {
+    let half_height = (Rad::from(Deg(camera.fovy)) / 2.0).tan();
+    Vector2::new(half_height * camera.aspect, half_height)
+}
+
+impl CameraController {
+    pub fn new(preset: CameraPreset, camera: &Camera, bounds: Bounds) -> Self {
+        let view = view_of(camera);
+        let mut controller = Self {
+            preset,
+            orbit: Orbit { target: Point3::origin(), yaw: Rad(0.0), tilt: Rad(0.0), distance: 1.0 },
+            goal: Orbit { target: Point3::origin(), yaw: Rad(0.0), tilt: Rad(0.0), distance: 1.0 },
+            bounds,
+            view,
+            dragging: false,
+            cursor: None,
+            last_update: Instant::now(),
+        };
+        controller.set_bounds(bounds, Point3::origin());
+        controller
+    }
+
+    /// How far back the camera has to be to see the whole board top down
+    fn fit_distance(&self) -> f32 {
+        let half_size = self.bounds.half_size;
+        (half_size.x / self.view.x).max(half_size.y / self.view.y) * FIT_MARGIN
+    }
+
+    fn preset_orbit(&self, preset: CameraPreset, head: Point3<f32>) -> Orbit {
+        let fit = self.fit_distance();
+        let follow = self.bounds.cell_size * FOLLOW_CELLS / 2.0 / self.view.y;
+        let (target, tilt, distance) = match preset {
+            CameraPreset::TopDown => (Point3::origin(), Deg(0.0), fit),
+            CameraPreset::ThreeQuarter => (Point3::origin(), Deg(40.0), fit * 1.2),
+            CameraPreset::Follow => (head, Deg(45.0), follow.min(fit)),
+        };
+        Orbit { target, yaw: Rad(0.0), tilt: tilt.into(), distance }
+    }
+
+    /// Looks at a new board, jumping straight to the preset's view of it
+    pub fn set_bounds(&mut self, bounds: Bounds, head: Point3<f32>) {
+        self.bounds = bounds;
+        self.goal = self.preset_orbit(self.preset, head);
+        self.orbit = self.goal;
+    }
+
+    /// Keeps the preset's view fitting after `camera` changes shape
+    pub fn set_aspect(&mut self, camera: &Camera) {
+        self.view = view_of(camera);
+        self.set_preset(self.preset);
     }
 
+    /// Eases into `preset`, keeping the turn given by dragging
+    pub fn set_preset(&mut self, preset: CameraPreset) {
+        self.preset = preset;
+        self.goal = Orbit { yaw: self.goal.yaw, ..self.preset_orbit(preset, self.goal.target) };
+    }
+
+    pub fn mouse_button(&mut self, pressed: bool) {
+        self.dragging = pressed;
+    }
+
+    /// Orbits by however far the cursor moved while dragging
+    pub fn cursor_moved(&mut self, x: f64, y: f64) {
+        if let (true, Some((last_x, last_y))) = (self.dragging, self.cursor) {
+            let (dx, dy) = ((x - last_x) as f32, (y - last_y) as f32);
+            let max_distance = self.fit_distance() * MAX_ZOOM_OUT;
+            // Applied straight away, following the mouse feels wrong if it lags
+            for orbit in [&mut self.orbit, &mut self.goal] {
+                orbit.yaw = (orbit.yaw - Rad(dx * DRAG_SENSITIVITY)).normalize();
+                orbit.tilt += Rad(dy * DRAG_SENSITIVITY);
+                orbit.clamp(max_distance);
+            }
+        }
+        self.cursor = Some((x, y));
+    }
+
+    pub fn cursor_left(&mut self) {
+        self.cursor = None;
+    }
+
+    pub fn scroll(&mut self, delta: MouseScrollDelta) {
+        let lines = match delta {
+            MouseScrollDelta::LineDelta(_, y) => y,
+            MouseScrollDelta::PixelDelta(position) => (position.y / PIXELS_PER_LINE) as f32,
+        };
+        self.goal.distance *= ZOOM_PER_LINE.powf(lines);
+        self.goal.clamp(self.fit_distance() * MAX_ZOOM_OUT);
+    }
+
+    /// Eases towards the goal for the time since the last update. `head` is
+    /// what the follow preset keeps in view.
+    pub fn update(&mut self, now: Instant, head: Point3<f32>) {
+        let elapsed = now.saturating_duration_since(self.last_update).as_secs_f32();
+        self.last_update = now;
+        if self.preset == CameraPreset::Follow {
+            self.follow(head);
+        }
+        self.orbit.approach(&self.goal, 1.0 - (-elapsed * SMOOTHING).exp());
+    }
+
+    /// Moves the goal just far enough to bring `head` back inside the dead
+    /// zone, then keeps it on the board
+    fn follow(&mut self, head: Point3<f32>) {
+        let board = self.bounds.half_size * 2.0;
+        let mut offset = head - self.goal.target;
+        if self.bounds.wraps {
+            // The head came back in on the other side, it is nearer round the seam
+            offset.x -= board.x * (offset.x / board.x).round();
+            offset.y -= board.y * (offset.y / board.y).round();
+        }
+        let dead_zone = self.view * (self.goal.distance * DEAD_ZONE);
+        let outside = |offset: f32, dead_zone: f32| offset - offset.clamp(-dead_zone, dead_zone);
+        self.goal.target.x += outside(offset.x, dead_zone.x);
+        self.goal.target.y += outside(offset.y, dead_zone.y);
+
+        let half_size = self.bounds.half_size;
+        if self.bounds.wraps {
+            // Past the seam the board carries on in ghost copies, so the
+            // camera is moved back a whole board where that can't be seen
+            let shift = Vector3::new(
+                -board.x * (self.goal.target.x / board.x).round(),
+                -board.y * (self.goal.target.y / board.y).round(),
+                0.0,
+            );
+            self.goal.target += shift;
+            self.orbit.target += shift;
+        } else {
+            // Stops at the edges, or stays in the middle if the board is
+            // smaller than the view
+            let reach = self.view * self.goal.distance;
+            let limit_x = (half_size.x - reach.x).max(0.0);
+            let limit_y = (half_size.y - reach.y).max(0.0);
+            self.goal.target.x = self.goal.target.x.clamp(-limit_x, limit_x);
+            self.goal.target.y = self.goal.target.y.clamp(-limit_y, limit_y);
+        }
+    }
+
+    /// How far past the board's edges the camera can see, where ghost copies
+    /// of whatever is across the seam need drawing. `None` unless following
+    /// on a wrapping board.
+    pub fn ghost_margin(&self) -> Option<f32> {
+        if self.preset != CameraPreset::Follow || !self.bounds.wraps {
+            return None;
+        }
+        let half_fov = self.view.y.atan();
+        let board = self.bounds.half_size * 2.0;
+        let mut reach = board.x.max(board.y);
+        // The far side of a tilted view reaches furthest, unless it sees
+        // the horizon
+        let far_angle = self.orbit.tilt.0 + half_fov;
+        if far_angle < std::f32::consts::FRAC_PI_2 * 0.95 {
+            let far = self.orbit.distance * half_fov.sin() / far_angle.cos();
+            reach = reach.min(far * self.view.x.max(self.view.y) / self.view.y + self.bounds.cell_size);
+        }
+        let distance_off_board = |target: f32, half_size: f32| (target.abs() - half_size).max(0.0);
+        let off_board = distance_off_board(self.orbit.target.x, self.bounds.half_size.x)
+            .max(distance_off_board(self.orbit.target.y, self.bounds.half_size.y));
+        Some(reach + off_board)
+    }
+}
+
+pub struct CameraStaging {
+    pub camera: Camera,
+    pub controller: CameraController,
+}
+
+impl CameraStaging {
+    pub fn new(camera: Camera, preset: CameraPreset, bounds: Bounds) -> Self {
+        let controller = CameraController::new(preset, &camera, bounds);
+        Self { camera, controller }
+    }
+
+    /// Points the camera where the controller has it
+    pub fn update_camera(&mut self, camera_uniform: &mut CameraUniform) {
+        let orbit = &self.controller.orbit;
+        self.camera.eye = orbit.eye();
+        self.camera.target = orbit.target;
+        self.camera.up = orbit.up();
+        camera_uniform.update_view_proj(&self.camera);
+    }
 }
- 
@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+pub use instant::Instant;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod instant {
+    pub use std::time::Instant;
+}
+
+/// `std::time::Instant` panics in the browser, this stands in for it there
+#[cfg(target_arch = "wasm32")]
+mod instant {
+    use std::{cmp::Ordering, ops::{Add, Sub}, time::Duration};
+
+    /// A point in time, in milliseconds since the page loaded
+    #[derive(Clone, Copy, Debug)]
+    pub struct Instant(f64);
+
+    impl Instant {
+        pub fn now() -> Self {
+            let performance = web_sys::window().and_then(|window| window.performance());
+            Self(performance.map_or_else(js_sys::Date::now, |performance| performance.now()))
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            self.saturating_duration_since(earlier)
+        }
+
+        pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+            Duration::from_secs_f64((self.0 - earlier.0).max(0.0) / 1000.0)
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Self::now().duration_since(*self)
+        }
+    }
+
+    impl PartialEq for Instant {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl Eq for Instant {}
+
+    impl PartialOrd for Instant {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Instant {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.total_cmp(&other.0)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, duration: Duration) -> Instant {
+            Instant(self.0 + duration.as_secs_f64() * 1000.0)
+        }
+    }
+
+    impl Sub<Duration> for Instant {
+        type Output = Instant;
+
+        fn sub(self, duration: Duration) -> Instant {
+            Instant(self.0 - duration.as_secs_f64() * 1000.0)
+        }
+    }
+
+    impl Sub<Instant> for Instant {
+        type Output = Duration;
+
+        fn sub(self, earlier: Instant) -> Duration {
+            self.duration_since(earlier)
+        }
+    }
+}
+
+/// Ticks due after an `advance`
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Due {
+    /// Ticks to run now
+    pub ticks: u32,
+    /// Ticks skipped because the loop fell too far behind
+    pub dropped: u32,
+}
+
+/// Turns however much time passed between frames into whole ticks of a
+/// fixed length, so the game runs at the same pace whatever the frame rate.
+/// Time left over carries on to the next frame.
+pub struct FixedTimestep {
+    pub step: Duration,
+    /// Most ticks run in one go after a stall, beyond that they are dropped
+    /// so the game doesn't race to catch up
+    pub max_catch_up: u32,
+    /// Time not yet turned into ticks, as of `last`
+    accumulated: Duration,
+    last: Instant,
+    /// Every tick dropped so far
+    pub dropped: u64,
+    /// Time stands still while set, see `pause`
+    paused: bool,
+}
+
+impl FixedTimestep {
+    pub fn new(step: Duration, max_catch_up: u32) -> Self {
+        Self { step, max_catch_up, accumulated: Duration::ZERO, last: Instant::now(), dropped: 0, paused: false }
+    }
+
+    /// Starts counting again from `now`, with nothing built up
+    pub fn reset(&mut self, now: Instant) {
+        self.accumulated = Duration::ZERO;
+        self.last = now;
+        self.paused = false;
+    }
+
+    /// Stops time at `now`, keeping how far it was into the current tick.
+    /// Anything past a whole tick is let go, as pausing may come late after
+    /// the page or window was away for a while.
+    pub fn pause(&mut self, now: Instant) {
+        if !self.paused {
+            self.accumulated = (self.accumulated + now.saturating_duration_since(self.last)).min(self.step);
+            self.paused = true;
+        }
+    }
+
+    /// Carries on from where `pause` left off, as if no time had passed
+    pub fn resume(&mut self, now: Instant) {
+        self.last = now;
+        self.paused = false;
+    }
+
+    /// Adds the time since the last call and takes out the ticks it makes up
+    pub fn advance(&mut self, now: Instant) -> Due {
+        if self.paused {
+            return Due::default();
+        }
+        self.accumulated += now.saturating_duration_since(self.last);
+        self.last = now;
+        let ticks = (self.accumulated.as_nanos() / self.step.as_nanos().max(1)).min(u32::MAX as u128) as u32;
+        self.accumulated -= self.step * ticks;
+        let dropped = ticks.saturating_sub(self.max_catch_up);
+        self.dropped += dropped as u64;
+        Due { ticks: ticks - dropped, dropped }
+    }
+
+    /// Hands back ticks from `advance` that couldn't run yet, so they are due
+    /// again next time
+    pub fn defer(&mut self, ticks: u32) {
+        self.accumulated += self.step * ticks.min(self.max_catch_up);
+    }
+
+    /// How far it is from the last tick to the next at `now`, from 0 to 1
+    pub fn alpha(&self, now: Instant) -> f32 {
+        let since_tick = match self.paused {
+            true => self.accumulated,
+            false => self.accumulated + now.saturating_duration_since(self.last),
+        };
+        (since_tick.as_secs_f32() / self.step.as_secs_f32()).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: Duration = Duration::from_millis(10);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// A timestep of `STEP` that starts counting at the returned instant
+    fn timestep(max_catch_up: u32) -> (FixedTimestep, Instant) {
+        let start = Instant::now();
+        let mut timestep = FixedTimestep::new(STEP, max_catch_up);
+        timestep.reset(start);
+        (timestep, start)
+    }
+
+    #[test]
+    fn advance_turns_time_into_whole_ticks() {
+        let (mut timestep, start) = timestep(10);
+        assert_eq!(timestep.advance(start + ms(4)), Due { ticks: 0, dropped: 0 });
+        assert_eq!(timestep.advance(start + ms(12)), Due { ticks: 1, dropped: 0 });
+        // The 2ms left over count towards the next ones
+        assert_eq!(timestep.advance(start + ms(30)), Due { ticks: 2, dropped: 0 });
+        assert_eq!(timestep.advance(start + ms(39)), Due { ticks: 0, dropped: 0 });
+        assert_eq!(timestep.advance(start + ms(55)), Due { ticks: 2, dropped: 0 });
+    }
+
+    #[test]
+    fn advance_drops_ticks_past_the_catch_up_limit() {
+        let (mut timestep, start) = timestep(3);
+        assert_eq!(timestep.advance(start + ms(105)), Due { ticks: 3, dropped: 7 });
+        // The part of a tick left over is kept
+        assert_eq!(timestep.advance(start + ms(112)), Due { ticks: 1, dropped: 0 });
+        assert_eq!(timestep.advance(start + ms(162)), Due { ticks: 3, dropped: 2 });
+        assert_eq!(timestep.dropped, 9);
+    }
+
+    #[test]
+    fn deferred_ticks_come_due_again() {
+        let (mut timestep, start) = timestep(3);
+        assert_eq!(timestep.advance(start + ms(20)).ticks, 2);
+        timestep.defer(2);
+        assert_eq!(timestep.advance(start + ms(20)).ticks, 2);
+        // No more than a catch up's worth is handed back
+        timestep.defer(50);
+        assert_eq!(timestep.advance(start + ms(20)), Due { ticks: 3, dropped: 0 });
+    }
+
+    #[test]
+    fn pausing_stops_time() {
+        let (mut timestep, start) = timestep(10);
+        timestep.pause(start + ms(4));
+        assert_eq!(timestep.advance(start + ms(500)), Due::default());
+        assert!((timestep.alpha(start + ms(500)) - 0.4).abs() < 1e-6);
+        timestep.resume(start + ms(1000));
+        assert_eq!(timestep.advance(start + ms(1005)), Due { ticks: 0, dropped: 0 });
+        assert_eq!(timestep.advance(start + ms(1006)), Due { ticks: 1, dropped: 0 });
+    }
+
+    #[test]
+    fn pausing_late_keeps_at_most_one_tick() {
+        let (mut timestep, start) = timestep(10);
+        timestep.pause(start + ms(2000));
+        timestep.resume(start + ms(3000));
+        assert_eq!(timestep.advance(start + ms(3000)), Due { ticks: 1, dropped: 0 });
+    }
+
+    #[test]
+    fn alpha_is_how_far_into_the_tick() {
+        let (mut timestep, start) = timestep(10);
+        assert_eq!(timestep.alpha(start), 0.0);
+        assert_eq!(timestep.alpha(start + ms(5)), 0.5);
+        timestep.advance(start + ms(12));
+        assert!((timestep.alpha(start + ms(13)) - 0.3).abs() < 1e-6);
+        // Never past the next tick, however late the frame
+        assert_eq!(timestep.alpha(start + ms(100)), 1.0);
+    }
+}
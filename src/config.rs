@@ -0,0 +1,190 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+
+use crate::{controller::ControllerKind, level::Level, model::ModelPaths, net::NetworkConditions, rollback::Netcode, snake::{random_seed, Board, Edges, GridPos, Rules, Spawn}};
+
+/// Everything about a game that can be tweaked without rebuilding, loaded from
+/// a JSON file where every field is optional.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub board: Board,
+    /// Milliseconds between moves
+    pub tick_ms: u64,
+    /// Width of one board cell in world units
+    pub cell_size: f32,
+    /// Inner size of the window in logical pixels
+    pub window_width: u32,
+    pub window_height: u32,
+    pub start_length: usize,
+    /// Where the first apple goes, random if not set
+    pub first_apple: Option<GridPos>,
+    pub edges: Edges,
+    pub walls: Vec<GridPos>,
+    pub obstacles: Vec<GridPos>,
+    /// Snakes on the board, one per player
+    pub players: usize,
+    /// Where each player starts, lined up across the board if not set
+    pub spawns: Vec<Spawn>,
+    /// Who steers each snake, in player order. Players past the end of the
+    /// list use the keyboard.
+    pub controllers: Vec<ControllerKind>,
+    /// Seed every game with this instead of a random one
+    pub seed: Option<u64>,
+    /// Served games run in lockstep with these settings, every player
+    /// simulating them. Otherwise the server simulates and streams snapshots.
+    pub lockstep: Option<Netcode>,
+    /// Fake a worse network when playing online
+    pub network: NetworkConditions,
+    /// Put on the high score table, and the name to join servers with
+    pub name: String,
+    /// Draw the board in 3D from a tilted camera instead of flat sprites,
+    /// V switches between them while playing
+    pub three_d: bool,
+    /// Files to draw in 3D in place of the built-in meshes
+    pub models: ModelPaths,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        let rules = Rules::default();
+        Self {
+            board: rules.board,
+            tick_ms: 64,
+            cell_size: 0.1,
+            window_width: 800,
+            window_height: 800,
+            start_length: rules.start_length,
+            first_apple: rules.first_apple,
+            edges: rules.edges,
+            walls: rules.walls,
+            obstacles: rules.obstacles,
+            players: rules.players,
+            spawns: rules.spawns,
+            controllers: Vec::new(),
+            seed: None,
+            lockstep: None,
+            network: NetworkConditions::default(),
+            name: "player".to_string(),
+            three_d: false,
+            models: ModelPaths::default(),
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_str(json).context("Config is not valid JSON")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("Couldn't load config {}", path.display()))
+    }
+
+    /// Checks the values make a playable game, call again after changing fields
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=10_000).contains(&self.tick_ms) {
+            anyhow::bail!("Tick must be between 1 and 10000 ms, got {}", self.tick_ms);
+        }
+        if !(self.cell_size > 0.0 && self.cell_size.is_finite()) {
+            anyhow::bail!("Cell size must be a positive number, got {}", self.cell_size);
+        }
+        if self.window_width == 0 || self.window_height == 0 {
+            anyhow::bail!("Window must be at least 1x1, got {}x{}", self.window_width, self.window_height);
+        }
+        if self.controllers.len() > self.players {
+            anyhow::bail!("Got {} controllers for {} players", self.controllers.len(), self.players);
+        }
+        if let Some(netcode) = &self.lockstep {
+            netcode.validate()?;
+        }
+        self.network.validate()?;
+        self.rules().validate()
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    /// The parts of the config that change how the game plays out
+    pub fn rules(&self) -> Rules {
+        Rules {
+            board: self.board,
+            start_length: self.start_length,
+            first_apple: self.first_apple,
+            edges: self.edges,
+            walls: self.walls.clone(),
+            obstacles: self.obstacles.clone(),
+            players: self.players,
+            spawns: self.spawns.clone(),
+        }
+    }
+
+    /// Replaces the board and everything on it with `level`, keeping the
+    /// number of players
+    pub fn set_level(&mut self, level: &Level) {
+        let rules = level.rules(self.players);
+        self.board = rules.board;
+        self.start_length = rules.start_length;
+        self.first_apple = rules.first_apple;
+        self.edges = rules.edges;
+        self.walls = rules.walls;
+        self.obstacles = rules.obstacles;
+        self.spawns = rules.spawns;
+    }
+
+    pub fn controller(&self, player: usize) -> ControllerKind {
+        self.controllers.get(player).copied().unwrap_or_default()
+    }
+
+    /// The configured seed, or a random one
+    pub fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(random_seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_out_of_range_values() {
+        let valid = GameConfig::default();
+        valid.validate().unwrap();
+        let broken = [
+            GameConfig { tick_ms: 0, ..valid.clone() },
+            GameConfig { tick_ms: 10_001, ..valid.clone() },
+            GameConfig { cell_size: 0.0, ..valid.clone() },
+            GameConfig { cell_size: f32::NAN, ..valid.clone() },
+            GameConfig { window_width: 0, ..valid.clone() },
+            GameConfig { controllers: vec![ControllerKind::Greedy; 2], ..valid.clone() },
+            GameConfig { board: Board::new(0, 10), ..valid.clone() },
+            GameConfig { players: 0, ..valid.clone() },
+            GameConfig { network: NetworkConditions { loss: 2.0, ..NetworkConditions::default() }, ..valid.clone() },
+            GameConfig { lockstep: Some(Netcode { input_delay: 100, max_prediction: 8 }), ..valid },
+        ];
+        for config in broken {
+            assert!(config.validate().is_err(), "accepted {config:?}");
+        }
+    }
+
+    #[test]
+    fn from_json_defaults_missing_fields() {
+        let config = GameConfig::from_json(r#"{ "tick_ms": 100, "board": { "width": 20, "height": 12 } }"#).unwrap();
+        assert_eq!(config, GameConfig { tick_ms: 100, board: Board::new(20, 12), ..GameConfig::default() });
+        assert_eq!(GameConfig::from_json("{}").unwrap(), GameConfig::default());
+    }
+
+    #[test]
+    fn from_json_rejects_bad_files() {
+        for json in [r#"{ "tick": 100 }"#, r#"{ "tick_ms": 0 }"#, r#"{ "tick_ms": "fast" }"#, "not json"] {
+            assert!(GameConfig::from_json(json).is_err(), "accepted {json}");
+        }
+    }
+}
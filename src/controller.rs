@@ -0,0 +1,407 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, str::FromStr};
+
+use crate::{snake::{Board, Edges, GridPos, InputQueue, Snake, SnakeState}, SnakeInputs};
+
+/// Every direction a snake can move in
+pub const DIRECTIONS: [SnakeInputs; 4] = [SnakeInputs::Up, SnakeInputs::Down, SnakeInputs::Left, SnakeInputs::Right];
+
+/// The game as one player sees it when deciding where to go
+#[derive(Clone, Copy)]
+pub struct GameView<'a> {
+    pub game: &'a SnakeState,
+    /// The snake being steered
+    pub player: usize,
+}
+
+impl<'a> GameView<'a> {
+    pub fn new(game: &'a SnakeState, player: usize) -> Self {
+        Self { game, player }
+    }
+
+    pub fn snake(&self) -> &'a Snake {
+        &self.game.snakes[self.player]
+    }
+
+    /// The cell reached by moving `direction` from `pos`, `None` past a solid edge
+    pub fn neighbour(&self, pos: GridPos, direction: SnakeInputs) -> Option<GridPos> {
+        let next = pos.step(direction);
+        let wrapped = self.game.rules.board.wrap(next);
+        (self.game.rules.edges == Edges::Wrap || wrapped == next).then_some(wrapped)
+    }
+
+    /// Steps between two cells, going around the edges if they wrap
+    pub fn distance(&self, a: GridPos, b: GridPos) -> i32 {
+        let Board { width, height } = self.game.rules.board;
+        let (dx, dy) = ((a.x - b.x).abs(), (a.y - b.y).abs());
+        match self.game.rules.edges {
+            Edges::Wrap => dx.min(width - dx) + dy.min(height - dy),
+            Edges::Solid => dx + dy,
+        }
+    }
+
+    /// Whether `pos` will be free of walls and snakes after the next tick,
+    /// assuming every moving snake's tail moves on
+    pub fn is_free(&self, pos: GridPos) -> bool {
+        if self.game.is_blocked(pos) {
+            return false;
+        }
+        self.game.snakes.iter().enumerate().all(|(i, snake)| {
+            let tail_moves = snake.alive && snake.direction.is_some() && !(i == self.player && Some(pos) == self.game.apple);
+            let kept = if tail_moves { snake.body.len() - 1 } else { snake.body.len() };
+            !snake.body[..kept].contains(&pos)
+        })
+    }
+
+    /// Whether moving the head `direction` on the next tick survives, not
+    /// counting where other heads go
+    pub fn is_safe(&self, direction: SnakeInputs) -> bool {
+        let snake = self.snake();
+        if direction == SnakeInputs::Stay || direction == snake.heading().opposite() {
+            return false;
+        }
+        self.neighbour(snake.head(), direction).is_some_and(|next| self.is_free(next))
+    }
+
+    pub fn safe_directions(&self) -> Vec<SnakeInputs> {
+        DIRECTIONS.into_iter().filter(|&d| self.is_safe(d)).collect()
+    }
+
+    /// Cells reachable from `start` without crossing anything, counting `start`
+    pub fn reachable_area(&self, start: GridPos) -> usize {
+        let mut seen = HashSet::from([start]);
+        let mut open = vec![start];
+        while let Some(pos) = open.pop() {
+            for d in DIRECTIONS {
+                if let Some(next) = self.neighbour(pos, d) {
+                    if self.is_free(next) && seen.insert(next) {
+                        open.push(next);
+                    }
+                }
+            }
+        }
+        seen.len()
+    }
+
+    /// The safe move leaving the most room, for when there is nothing better to do
+    pub fn roomiest_direction(&self) -> SnakeInputs {
+        let head = self.snake().head();
+        self.safe_directions()
+            .into_iter()
+            .max_by_key(|&d| self.neighbour(head, d).map_or(0, |next| self.reachable_area(next)))
+            .unwrap_or(SnakeInputs::Stay)
+    }
+}
+
+/// Decides where one snake goes, consulted once per tick
+pub trait Controller {
+    /// The turn to make on the coming tick, `Stay` to carry on as before
+    fn next_input(&mut self, view: &GameView) -> SnakeInputs;
+
+    /// Offered each turn a player presses, returns whether it was taken
+    fn steer(&mut self, _direction: SnakeInputs, _view: &GameView) -> bool {
+        false
+    }
+}
+
+/// A human at the keyboard, turns are queued as they are pressed
+#[derive(Default)]
+pub struct Keyboard {
+    pub queue: InputQueue,
+}
+
+impl Controller for Keyboard {
+    fn next_input(&mut self, _view: &GameView) -> SnakeInputs {
+        self.queue.pop().unwrap_or(SnakeInputs::Stay)
+    }
+
+    fn steer(&mut self, direction: SnakeInputs, view: &GameView) -> bool {
+        self.queue.push(direction, view.snake().heading())
+    }
+}
+
+/// Heads straight for the apple, only avoiding what is right in front of it
+#[derive(Default)]
+pub struct Greedy;
+
+impl Controller for Greedy {
+    fn next_input(&mut self, view: &GameView) -> SnakeInputs {
+        let head = view.snake().head();
+        let Some(apple) = view.game.apple else {
+            return view.roomiest_direction();
+        };
+        view.safe_directions()
+            .into_iter()
+            .min_by_key(|&d| view.neighbour(head, d).map_or(i32::MAX, |next| view.distance(next, apple)))
+            .unwrap_or(SnakeInputs::Stay)
+    }
+}
+
+/// Follows the shortest path to the apple around walls and bodies, or plays
+/// for space when there is none
+#[derive(Default)]
+pub struct Pathfinder;
+
+impl Pathfinder {
+    /// First move of a shortest path from the head to `goal`
+    fn first_step(view: &GameView, goal: GridPos) -> Option<SnakeInputs> {
+        let head = view.snake().head();
+        let mut came_from: HashMap<GridPos, (GridPos, SnakeInputs)> = HashMap::new();
+        let mut open = VecDeque::from([head]);
+        while let Some(pos) = open.pop_front() {
+            if pos == goal {
+                let mut pos = goal;
+                loop {
+                    let (previous, direction) = came_from[&pos];
+                    if previous == head {
+                        return Some(direction);
+                    }
+                    pos = previous;
+                }
+            }
+            for d in DIRECTIONS {
+                if pos == head && !view.is_safe(d) {
+                    continue;
+                }
+                let Some(next) = view.neighbour(pos, d) else {
+                    continue;
+                };
+                if next != head && view.is_free(next) && !came_from.contains_key(&next) {
+                    came_from.insert(next, (pos, d));
+                    open.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Controller for Pathfinder {
+    fn next_input(&mut self, view: &GameView) -> SnakeInputs {
+        view.game.apple
+            .and_then(|apple| Self::first_step(view, apple))
+            .unwrap_or_else(|| view.roomiest_direction())
+    }
+}
+
+/// A path through every cell of the board that ends where it started
+struct Cycle {
+    order: Vec<GridPos>,
+    /// Where each cell comes in `order`
+    index: HashMap<GridPos, usize>,
+}
+
+impl Cycle {
+    /// Builds a cycle through every cell: along the bottom row, back and forth
+    /// over the rest but for the left column, then down that column home.
+    /// Needs an even number of rows, the board is transposed otherwise.
+    fn new(board: Board) -> Option<Self> {
+        let Board { width, height } = board;
+        let transpose = height % 2 != 0;
+        let (columns, rows) = if transpose { (height, width) } else { (width, height) };
+        if rows % 2 != 0 {
+            return None;
+        }
+        let mut order: Vec<GridPos> = (0..columns).map(|x| GridPos::new(x, 0)).collect();
+        for y in 1..rows {
+            let sweep: Vec<i32> = if y % 2 == 1 { (1..columns).rev().collect() } else { (1..columns).collect() };
+            order.extend(sweep.into_iter().map(|x| GridPos::new(x, y)));
+        }
+        order.extend((1..rows).rev().map(|y| GridPos::new(0, y)));
+        if transpose {
+            for pos in &mut order {
+                *pos = GridPos::new(pos.y, pos.x);
+            }
+        }
+        let index = order.iter().enumerate().map(|(i, &pos)| (pos, i)).collect();
+        Some(Self { order, index })
+    }
+
+    fn successor(&self, pos: GridPos) -> GridPos {
+        self.order[(self.index[&pos] + 1) % self.order.len()]
+    }
+
+    /// Steps forward round the cycle from `from` to `to`
+    fn distance(&self, from: GridPos, to: GridPos) -> usize {
+        (self.index[&to] + self.order.len() - self.index[&from]) % self.order.len()
+    }
+
+    /// Whether `body`, head first, comes in cycle order from the tail up to
+    /// the head. Then every cell from just past the head round to the tail
+    /// is free, so following the cycle can never run into it.
+    fn holds(&self, body: &[GridPos]) -> bool {
+        let tail = body[body.len() - 1];
+        body.windows(2).all(|pair| self.distance(tail, pair[0]) > self.distance(tail, pair[1]))
+    }
+}
+
+/// Walks a fixed cycle through every cell of the board, which fills it
+/// without ever getting stuck. Boards with walls or two odd sides have no such
+/// cycle, there it plays like `Pathfinder`.
+///
+/// Once its body lies along the cycle it keeps it there, cutting ahead
+/// towards the apple only as far as it can without passing its own tail.
+/// Until then, such as at the start, it heads for a stretch of the cycle its
+/// body will be out of the way of by the time it gets round.
+#[derive(Default)]
+pub struct Hamiltonian {
+    /// Board the cycle was built for
+    cycle: Option<(Board, Cycle)>,
+}
+
+impl Hamiltonian {
+    fn cycle(&mut self, view: &GameView) -> Option<&Cycle> {
+        let rules = &view.game.rules;
+        if !rules.walls.is_empty() || !rules.obstacles.is_empty() {
+            return None;
+        }
+        if self.cycle.as_ref().is_none_or(|(board, _)| *board != rules.board) {
+            self.cycle = Cycle::new(rules.board).map(|cycle| (rules.board, cycle));
+        }
+        self.cycle.as_ref().map(|(_, cycle)| cycle)
+    }
+
+    /// The safe move furthest along the cycle that neither passes the apple
+    /// nor reaches the tail
+    fn shortcut(cycle: &Cycle, view: &GameView) -> Option<SnakeInputs> {
+        let snake = view.snake();
+        let head = snake.head();
+        let room = cycle.distance(head, snake.body[snake.body.len() - 1]);
+        let goal = view.game.apple.map_or(1, |apple| cycle.distance(head, apple));
+        view.safe_directions()
+            .into_iter()
+            .filter_map(|d| {
+                let ahead = cycle.distance(head, view.neighbour(head, d)?);
+                // The next cell on the cycle is always fine, it is free or the tail moving off
+                (ahead == 1 || (ahead < room && ahead <= goal)).then_some((ahead, d))
+            })
+            .max_by_key(|&(ahead, _)| ahead)
+            .map(|(_, d)| d)
+    }
+
+    /// A safe move onto a stretch of the cycle that each segment of the body
+    /// has left by the time the head gets there, with a tick to spare for
+    /// growing. Going on along the cycle from there lays the body on it.
+    fn join(cycle: &Cycle, view: &GameView) -> Option<SnakeInputs> {
+        let body = &view.snake().body;
+        let head = body[0];
+        let clear = |start: GridPos| {
+            std::iter::successors(Some(start), |&pos| Some(cycle.successor(pos)))
+                .take(body.len())
+                .enumerate()
+                .all(|(ticks, pos)| body.iter().position(|&segment| segment == pos).is_none_or(|i| i + ticks >= body.len()))
+        };
+        let along = cycle.successor(head);
+        view.safe_directions()
+            .into_iter()
+            .filter(|&d| view.neighbour(head, d).is_some_and(clear))
+            .min_by_key(|&d| view.neighbour(head, d) != Some(along))
+    }
+}
+
+impl Controller for Hamiltonian {
+    fn next_input(&mut self, view: &GameView) -> SnakeInputs {
+        let chosen = self.cycle(view).and_then(|cycle| {
+            if cycle.holds(&view.snake().body) {
+                Self::shortcut(cycle, view)
+            } else {
+                Self::join(cycle, view)
+            }
+        });
+        // Other snakes can still get in the way
+        chosen.unwrap_or_else(|| Pathfinder.next_input(view))
+    }
+}
+
+/// Who steers a snake, as picked in the config
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum ControllerKind {
+    #[default]
+    Keyboard,
+    Greedy,
+    Pathfinder,
+    Hamiltonian,
+}
+
+impl ControllerKind {
+    pub const ALL: [ControllerKind; 4] = [Self::Keyboard, Self::Greedy, Self::Pathfinder, Self::Hamiltonian];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Keyboard => "keyboard",
+            Self::Greedy => "greedy",
+            Self::Pathfinder => "pathfinder",
+            Self::Hamiltonian => "hamiltonian",
+        }
+    }
+
+    pub fn create(self) -> Box<dyn Controller> {
+        match self {
+            Self::Keyboard => Box::<Keyboard>::default(),
+            Self::Greedy => Box::new(Greedy),
+            Self::Pathfinder => Box::new(Pathfinder),
+            Self::Hamiltonian => Box::<Hamiltonian>::default(),
+        }
+    }
+}
+
+impl FromStr for ControllerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names = Self::ALL.map(Self::name).join(", ");
+                anyhow::anyhow!("Unknown controller {s}, expected one of {names}")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snake::{Rules, Spawn, StepOutcome};
+
+    /// Lets a lone `Hamiltonian` snake play until the game ends
+    fn play_out(rules: Rules, seed: u64) -> SnakeState {
+        let cells = (rules.board.width * rules.board.height) as usize;
+        let mut game = SnakeState::new(rules, seed);
+        game.start();
+        let mut bot = Hamiltonian::default();
+        // Each apple is at most one lap away
+        for _ in 0..cells * cells {
+            let input = bot.next_input(&GameView::new(&game, 0));
+            if matches!(game.step(&[Some(input)]), StepOutcome::Died | StepOutcome::Won) {
+                break;
+            }
+        }
+        game
+    }
+
+    #[test]
+    fn hamiltonian_fills_the_board() {
+        for (width, height) in [(4, 4), (6, 5), (5, 6), (6, 7), (7, 4)] {
+            for edges in [Edges::Solid, Edges::Wrap] {
+                for seed in 0..10 {
+                    let rules = Rules { board: Board::new(width, height), edges, start_length: 3, ..Rules::default() };
+                    let game = play_out(rules, seed);
+                    assert!(game.won, "died on a {width}x{height} {edges:?} board with seed {seed} at length {}", game.snakes[0].length());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hamiltonian_gets_onto_the_cycle_from_a_long_start() {
+        // Laid the wrong way along the cycle, so it has to turn off it first
+        for (width, height, start_length, row) in [(6, 5, 5, 0), (6, 5, 6, 0), (6, 7, 6, 0), (7, 4, 7, 1)] {
+            let spawn = Spawn { head: GridPos::new(width - 1, row), direction: SnakeInputs::Right };
+            for seed in 0..10 {
+                let rules = Rules { board: Board::new(width, height), edges: Edges::Solid, start_length, spawns: vec![spawn], ..Rules::default() };
+                let game = play_out(rules, seed);
+                assert!(game.won, "died on a {width}x{height} board with seed {seed} at length {}", game.snakes[0].length());
+            }
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use crate::{
+    controller::{Controller, ControllerKind, GameView, DIRECTIONS},
+    snake::{Edges, GridPos, Rules, SnakeState, StepOutcome},
+    SnakeInputs,
+};
+
+/// How an agent is rewarded, added up over each step
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rewards {
+    pub apple: f32,
+    pub death: f32,
+    /// For filling the board
+    pub win: f32,
+    /// Every step, negative to hurry the agent along
+    pub step: f32,
+    /// Per cell the head moved towards the apple, taken away when it moves away
+    pub approach: f32,
+}
+
+impl Default for Rewards {
+    fn default() -> Self {
+        Self {
+            apple: 1.0,
+            death: -1.0,
+            win: 10.0,
+            step: 0.0,
+            approach: 0.0,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum ObservationKind {
+    /// `[GRID_CHANNELS, height, width]` planes of ones and zeros, see `GRID_CHANNELS`
+    #[default]
+    Grid,
+    /// `FEATURE_COUNT` numbers, see `SnakeEnv::features`
+    Features,
+}
+
+/// Planes of a grid observation: the agent's head, its body, the apple,
+/// walls and obstacles, then every other snake
+pub const GRID_CHANNELS: usize = 5;
+
+/// Length of a feature observation
+pub const FEATURE_COUNT: usize = 11;
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvConfig {
+    pub rules: Rules,
+    pub rewards: Rewards,
+    pub observation: ObservationKind,
+    /// Cut episodes off after this many ticks
+    pub max_ticks: Option<u32>,
+    /// Cut episodes off after this many ticks without an apple
+    pub hunger_limit: Option<u32>,
+    /// Bots steering players 2 and up when the rules have several
+    pub opponents: Vec<ControllerKind>,
+}
+
+/// A flat tensor, `data` is laid out row-major in `shape`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepInfo {
+    pub outcome: StepOutcome,
+    pub score: u32,
+    pub length: usize,
+    pub ticks: u32,
+    /// The episode hit `max_ticks` or `hunger_limit` rather than ending in the game
+    pub truncated: bool,
+}
+
+/// Snake as a reinforcement learning environment. The agent is player 1 and
+/// any other players are driven by bots.
+///
+/// Nothing here touches the GPU or a window, only the game rules.
+pub struct SnakeEnv {
+    pub config: EnvConfig,
+    pub game: SnakeState,
+    opponents: Vec<Box<dyn Controller>>,
+    /// Tick the agent last ate on
+    last_meal: u32,
+    /// The episode is over, set by `step` and cleared by `reset`
+    pub done: bool,
+}
+
+impl SnakeEnv {
+    pub fn new(config: EnvConfig) -> anyhow::Result<Self> {
+        config.rules.validate()?;
+        let game = SnakeState::new(config.rules.clone(), 0);
+        let mut env = Self { config, game, opponents: Vec::new(), last_meal: 0, done: false };
+        env.reset(0);
+        Ok(env)
+    }
+
+    /// Starts a new episode, the same seed always gives the same episode
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        self.game = SnakeState::new(self.config.rules.clone(), seed);
+        self.game.start();
+        self.opponents = (1..self.config.rules.players)
+            .map(|player| self.config.opponents.get(player - 1).copied().unwrap_or(ControllerKind::Greedy).create())
+            .collect();
+        self.last_meal = 0;
+        self.done = false;
+        self.observe()
+    }
+
+    /// Moves the agent `action` for one tick, `Stay` keeps its heading.
+    /// Once `done`, further steps do nothing until the next `reset`.
+    pub fn step(&mut self, action: SnakeInputs) -> (Observation, f32, bool, StepInfo) {
+        if self.done {
+            return (self.observe(), 0.0, true, self.info(StepOutcome::Idle, false));
+        }
+        let rewards = &self.config.rewards;
+        let before = self.apple_distance();
+        let score = self.game.snakes[0].score;
+
+        let mut inputs = vec![Some(action).filter(|&action| action != SnakeInputs::Stay)];
+        for (i, opponent) in self.opponents.iter_mut().enumerate() {
+            let input = opponent.next_input(&GameView::new(&self.game, i + 1));
+            inputs.push(Some(input).filter(|&input| input != SnakeInputs::Stay));
+        }
+        let outcome = self.game.step(&inputs);
+
+        let agent = &self.game.snakes[0];
+        let ate = agent.score > score;
+        if ate {
+            self.last_meal = self.game.ticks;
+        }
+        let mut reward = 0.0;
+        if outcome != StepOutcome::Idle {
+            reward += rewards.step;
+        }
+        if ate {
+            reward += rewards.apple;
+        } else if let (Some(before), Some(after)) = (before, self.apple_distance()) {
+            reward += rewards.approach * (before - after) as f32;
+        }
+        if !agent.alive {
+            reward += rewards.death;
+        }
+        if self.game.won && self.game.winner.is_none_or(|winner| winner == 0) {
+            reward += rewards.win;
+        }
+
+        let truncated = !self.game.ended
+            && (self.config.max_ticks.is_some_and(|max| self.game.ticks >= max)
+                || self.config.hunger_limit.is_some_and(|limit| self.game.ticks - self.last_meal >= limit));
+        self.done = self.game.ended || !agent.alive || truncated;
+        (self.observe(), reward, self.done, self.info(outcome, truncated))
+    }
+
+    fn info(&self, outcome: StepOutcome, truncated: bool) -> StepInfo {
+        let agent = &self.game.snakes[0];
+        StepInfo {
+            outcome,
+            score: agent.score,
+            length: agent.length(),
+            ticks: self.game.ticks,
+            truncated,
+        }
+    }
+
+    /// Steps from the agent's head to the apple, ignoring anything in the way
+    fn apple_distance(&self) -> Option<i32> {
+        let apple = self.game.apple?;
+        Some(GameView::new(&self.game, 0).distance(self.game.snakes[0].head(), apple))
+    }
+
+    /// The current state in the configured form
+    pub fn observe(&self) -> Observation {
+        match self.config.observation {
+            ObservationKind::Grid => self.grid(),
+            ObservationKind::Features => self.features(),
+        }
+    }
+
+    pub fn observation_shape(&self) -> Vec<usize> {
+        let board = self.game.rules.board;
+        match self.config.observation {
+            ObservationKind::Grid => vec![GRID_CHANNELS, board.height as usize, board.width as usize],
+            ObservationKind::Features => vec![FEATURE_COUNT],
+        }
+    }
+
+    /// The board as planes of `[channel, y, x]`, see `GRID_CHANNELS`
+    pub fn grid(&self) -> Observation {
+        let board = self.game.rules.board;
+        let (width, height) = (board.width as usize, board.height as usize);
+        let mut data = vec![0.0; GRID_CHANNELS * width * height];
+        let mut mark = |channel: usize, pos: GridPos| {
+            data[(channel * height + pos.y as usize) * width + pos.x as usize] = 1.0;
+        };
+        for (player, snake) in self.game.snakes.iter().enumerate() {
+            for (i, &pos) in snake.body.iter().enumerate() {
+                let channel = match (player, i) {
+                    (0, 0) => 0,
+                    (0, _) => 1,
+                    _ => 4,
+                };
+                mark(channel, pos);
+            }
+        }
+        if let Some(apple) = self.game.apple {
+            mark(2, apple);
+        }
+        for &pos in self.game.rules.walls.iter().chain(&self.game.rules.obstacles) {
+            mark(3, pos);
+        }
+        Observation { shape: vec![GRID_CHANNELS, height, width], data }
+    }
+
+    /// A summary from the agent's head: whether moving up, down, left or
+    /// right is deadly, its heading one-hot in the same order, the shortest
+    /// offset to the apple as a fraction of the board, and the share of the
+    /// board it covers
+    pub fn features(&self) -> Observation {
+        let view = GameView::new(&self.game, 0);
+        let snake = view.snake();
+        let board = self.game.rules.board;
+        let mut data = Vec::with_capacity(FEATURE_COUNT);
+        for d in DIRECTIONS {
+            let deadly = d != snake.heading().opposite() && !view.is_safe(d);
+            data.push(deadly as u8 as f32);
+        }
+        for d in DIRECTIONS {
+            data.push((snake.heading() == d) as u8 as f32);
+        }
+        let (dx, dy) = match self.game.apple {
+            Some(apple) => {
+                let offset = |from: i32, to: i32, size: i32| {
+                    let delta = to - from;
+                    match self.game.rules.edges {
+                        // Whichever way round is shorter
+                        Edges::Wrap => (delta + size / 2).rem_euclid(size) - size / 2,
+                        Edges::Solid => delta,
+                    }
+                };
+                let head = snake.head();
+                (offset(head.x, apple.x, board.width), offset(head.y, apple.y, board.height))
+            },
+            None => (0, 0),
+        };
+        data.push(dx as f32 / board.width as f32);
+        data.push(dy as f32 / board.height as f32);
+        data.push(snake.length() as f32 / (board.width * board.height) as f32);
+        Observation { shape: vec![FEATURE_COUNT], data }
+    }
+}
+
+/// The result of stepping every environment in a `VecEnv` once, with
+/// observations stacked into one tensor
+#[derive(Clone, Debug)]
+pub struct BatchStep {
+    /// `[environments, ..observation shape]`
+    pub observations: Observation,
+    pub rewards: Vec<f32>,
+    pub dones: Vec<bool>,
+    pub infos: Vec<StepInfo>,
+}
+
+/// Many environments stepped together. Finished episodes restart straight
+/// away, so the observation returned for a done environment is the first one
+/// of its next episode.
+pub struct VecEnv {
+    pub envs: Vec<SnakeEnv>,
+    /// Seed for the next episode to start
+    next_seed: u64,
+}
+
+impl VecEnv {
+    pub fn new(config: EnvConfig, count: usize) -> anyhow::Result<Self> {
+        let envs = (0..count).map(|_| SnakeEnv::new(config.clone())).collect::<anyhow::Result<_>>()?;
+        Ok(Self { envs, next_seed: 0 })
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    /// Restarts every environment, seeding them `seed`, `seed + 1` and so on.
+    /// Later episodes carry on counting up from there.
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        self.next_seed = seed;
+        let observations = (0..self.envs.len())
+            .map(|i| {
+                let seed = self.take_seed();
+                self.envs[i].reset(seed)
+            })
+            .collect::<Vec<_>>();
+        self.stack(observations)
+    }
+
+    /// Steps environment `i` with `actions[i]`
+    pub fn step(&mut self, actions: &[SnakeInputs]) -> BatchStep {
+        assert_eq!(actions.len(), self.envs.len(), "One action is needed per environment");
+        let mut observations = Vec::with_capacity(self.envs.len());
+        let mut rewards = Vec::with_capacity(self.envs.len());
+        let mut dones = Vec::with_capacity(self.envs.len());
+        let mut infos = Vec::with_capacity(self.envs.len());
+        for (i, &action) in actions.iter().enumerate() {
+            let (mut observation, reward, done, info) = self.envs[i].step(action);
+            if done {
+                let seed = self.take_seed();
+                observation = self.envs[i].reset(seed);
+            }
+            observations.push(observation);
+            rewards.push(reward);
+            dones.push(done);
+            infos.push(info);
+        }
+        BatchStep { observations: self.stack(observations), rewards, dones, infos }
+    }
+
+    fn take_seed(&mut self) -> u64 {
+        let seed = self.next_seed;
+        self.next_seed = self.next_seed.wrapping_add(1);
+        seed
+    }
+
+    fn stack(&self, observations: Vec<Observation>) -> Observation {
+        let mut shape = vec![observations.len()];
+        shape.extend(observations.first().map_or_else(Vec::new, |first| first.shape.clone()));
+        let data = observations.into_iter().flat_map(|observation| observation.data).collect();
+        Observation { shape, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snake::Board;
+
+    /// A lone snake on an 8x8 board, starting at (4, 4) facing left with
+    /// the first apple where `first_apple` says
+    fn config(edges: Edges, first_apple: GridPos) -> EnvConfig {
+        EnvConfig {
+            rules: Rules { board: Board::new(8, 8), edges, first_apple: Some(first_apple), ..Rules::default() },
+            ..EnvConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_same_seed_plays_the_same_episode() {
+        let config = EnvConfig { rules: Rules { players: 2, ..Rules::default() }, ..EnvConfig::default() };
+        let mut envs = [SnakeEnv::new(config.clone()).unwrap(), SnakeEnv::new(config).unwrap()];
+        let [first, second] = &mut envs;
+        assert_eq!(first.reset(5), second.reset(5));
+        let actions = [SnakeInputs::Stay, SnakeInputs::Up, SnakeInputs::Left, SnakeInputs::Down, SnakeInputs::Stay];
+        for action in actions.iter().cycle().take(40) {
+            assert_eq!(first.step(*action), second.step(*action));
+        }
+    }
+
+    #[test]
+    fn observations_have_the_advertised_shape() {
+        for observation in [ObservationKind::Grid, ObservationKind::Features] {
+            let config = EnvConfig {
+                rules: Rules { board: Board::new(12, 7), ..Rules::default() },
+                observation,
+                ..EnvConfig::default()
+            };
+            let mut env = SnakeEnv::new(config).unwrap();
+            let shape = env.observation_shape();
+            for observation in [env.reset(1), env.step(SnakeInputs::Up).0] {
+                assert_eq!(observation.shape, shape);
+                assert_eq!(observation.data.len(), shape.iter().product::<usize>());
+            }
+        }
+        let env = SnakeEnv::new(config(Edges::Wrap, GridPos::new(0, 0))).unwrap();
+        assert_eq!(env.grid().data.len(), GRID_CHANNELS * 8 * 8);
+        assert_eq!(env.features().data.len(), FEATURE_COUNT);
+        // One head, one body segment, one apple
+        assert_eq!(env.grid().data.iter().sum::<f32>(), 3.0);
+    }
+
+    #[test]
+    fn rewards_approach_apples_and_death() {
+        let mut config = config(Edges::Solid, GridPos::new(2, 4));
+        config.rewards = Rewards { apple: 1.0, death: -1.0, win: 10.0, step: -0.5, approach: 0.25 };
+        let mut env = SnakeEnv::new(config).unwrap();
+        env.reset(3);
+        let (_, reward, done, _) = env.step(SnakeInputs::Stay);
+        assert_eq!((reward, done), (-0.25, false));
+        let (_, reward, _, info) = env.step(SnakeInputs::Stay);
+        assert_eq!((reward, info.outcome, info.score), (0.5, StepOutcome::AteApple, 1));
+        // Straight on into the left edge
+        let mut last = env.step(SnakeInputs::Stay);
+        while !last.2 {
+            last = env.step(SnakeInputs::Stay);
+        }
+        let (_, reward, _, info) = last;
+        assert_eq!((reward, info.outcome, info.truncated), (-1.5, StepOutcome::Died, false));
+    }
+
+    #[test]
+    fn episodes_are_cut_off_at_the_limits() {
+        let limits = [(Some(3), None, 3), (None, Some(2), 2)];
+        for (max_ticks, hunger_limit, ticks) in limits {
+            let config = EnvConfig { max_ticks, hunger_limit, ..config(Edges::Wrap, GridPos::new(4, 0)) };
+            let mut env = SnakeEnv::new(config).unwrap();
+            for _ in 1..ticks {
+                assert!(!env.step(SnakeInputs::Stay).2);
+            }
+            let (_, _, done, info) = env.step(SnakeInputs::Stay);
+            assert!(done && info.truncated);
+            assert_eq!(info.ticks, ticks);
+            let (_, reward, done, info) = env.step(SnakeInputs::Stay);
+            assert_eq!((reward, done, info.outcome), (0.0, true, StepOutcome::Idle));
+        }
+    }
+
+    #[test]
+    fn vec_env_restarts_finished_episodes_on_the_next_seed() {
+        let config = EnvConfig { max_ticks: Some(2), ..config(Edges::Wrap, GridPos::new(4, 0)) };
+        let mut envs = VecEnv::new(config, 2).unwrap();
+        let observations = envs.reset(10);
+        assert_eq!(observations.shape, [2, GRID_CHANNELS, 8, 8]);
+        assert_eq!(envs.envs.iter().map(|env| env.game.seed).collect::<Vec<_>>(), [10, 11]);
+        let batch = envs.step(&[SnakeInputs::Stay; 2]);
+        assert_eq!(batch.dones, [false, false]);
+        let batch = envs.step(&[SnakeInputs::Up, SnakeInputs::Stay]);
+        assert_eq!(batch.dones, [true, true]);
+        assert!(batch.infos.iter().all(|info| info.truncated && info.ticks == 2));
+        assert_eq!(envs.envs.iter().map(|env| env.game.seed).collect::<Vec<_>>(), [12, 13]);
+        assert!(envs.envs.iter().all(|env| env.game.ticks == 0 && !env.done));
+        // What comes back is the start of the next episode
+        let restarted = envs.envs.iter().flat_map(|env| env.observe().data).collect::<Vec<_>>();
+        assert_eq!(batch.observations.data, restarted);
+    }
+}
@@ -0,0 +1,245 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::{snake::{Board, Edges, GridPos, Rules, Spawn, MAX_BOARD_SIZE, MAX_PLAYERS}, SnakeInputs};
+
+/// Levels compiled into the game, playable by name
+pub const BUILTIN_LEVELS: &[(&str, &str)] = &[
+    ("box", include_str!("../levels/box.txt")),
+    ("pillars", include_str!("../levels/pillars.txt")),
+    ("tunnels", include_str!("../levels/tunnels.txt")),
+];
+
+/// A designed board, loaded from JSON or from a text map.
+///
+/// A text map is a few optional `key: value` lines followed by one line per
+/// row of the board, top row first:
+///
+/// ```text
+/// ; comments start with a semicolon
+/// name: Box
+/// edges: solid
+/// length: 3
+/// ######
+/// #.<..#
+/// #..*.#
+/// ######
+/// ```
+///
+/// `.` is an empty cell, `#` a wall, `O` an obstacle and `*` the first apple.
+/// Each player's head starts on an arrow, `^`, `v`, `<` or `>`, facing the way
+/// it points. Players take the arrows in reading order.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Level {
+    pub name: String,
+    pub board: Board,
+    pub edges: Edges,
+    pub walls: Vec<GridPos>,
+    pub obstacles: Vec<GridPos>,
+    pub spawns: Vec<Spawn>,
+    pub start_length: usize,
+    pub first_apple: Option<GridPos>,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        let rules = Rules::default();
+        Self {
+            name: String::new(),
+            board: rules.board,
+            edges: rules.edges,
+            walls: rules.walls,
+            obstacles: rules.obstacles,
+            spawns: rules.spawns,
+            start_length: rules.start_length,
+            first_apple: rules.first_apple,
+        }
+    }
+}
+
+impl Level {
+    /// The rules for playing this level with `players` snakes
+    pub fn rules(&self, players: usize) -> Rules {
+        Rules {
+            board: self.board,
+            start_length: self.start_length,
+            first_apple: self.first_apple,
+            edges: self.edges,
+            walls: self.walls.clone(),
+            obstacles: self.obstacles.clone(),
+            players,
+            spawns: self.spawns.clone(),
+        }
+    }
+
+    /// Checks the level is playable by as many players as it has spawns
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.rules(self.spawns.len().max(1)).validate()
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let level: Self = serde_json::from_str(json).context("Level is not valid JSON")?;
+        level.validate()?;
+        Ok(level)
+    }
+
+    pub fn from_text(text: &str) -> anyhow::Result<Self> {
+        let mut level = Self::default();
+        let mut rows = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let number = number + 1;
+            let line = line.trim_end();
+            if line.starts_with(';') || (rows.is_empty() && line.is_empty()) {
+                continue;
+            }
+            if rows.is_empty() {
+                if let Some((key, value)) = line.split_once(':') {
+                    level.set_header(key.trim(), value.trim()).with_context(|| format!("Line {number}"))?;
+                    continue;
+                }
+            }
+            rows.push((number, line));
+        }
+        while rows.last().is_some_and(|(_, row)| row.is_empty()) {
+            rows.pop();
+        }
+        if rows.is_empty() {
+            anyhow::bail!("Level has no map");
+        }
+
+        let width = rows[0].1.chars().count();
+        let height = rows.len();
+        if width > MAX_BOARD_SIZE as usize || height > MAX_BOARD_SIZE as usize {
+            anyhow::bail!("Map is {width}x{height} cells, the most is {0}x{0}", MAX_BOARD_SIZE);
+        }
+        level.board = Board::new(width as i32, height as i32);
+        for (i, &(number, row)) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                anyhow::bail!("Line {number}: every map row must be {width} cells wide like the first, this one is {}", row.chars().count());
+            }
+            // The first row is the top of the board
+            let y = (height - 1 - i) as i32;
+            for (x, cell) in row.chars().enumerate() {
+                let pos = GridPos::new(x as i32, y);
+                let direction = match cell {
+                    '.' => continue,
+                    '#' => {
+                        level.walls.push(pos);
+                        continue;
+                    },
+                    'O' | 'o' => {
+                        level.obstacles.push(pos);
+                        continue;
+                    },
+                    '*' => {
+                        if level.first_apple.replace(pos).is_some() {
+                            anyhow::bail!("Line {number}: the map has more than one apple");
+                        }
+                        continue;
+                    },
+                    '^' => SnakeInputs::Up,
+                    'v' => SnakeInputs::Down,
+                    '<' => SnakeInputs::Left,
+                    '>' => SnakeInputs::Right,
+                    _ => anyhow::bail!("Line {number}: unknown map cell '{cell}'"),
+                };
+                if level.spawns.len() == MAX_PLAYERS {
+                    anyhow::bail!("Line {number}: the map has more than {MAX_PLAYERS} snake heads");
+                }
+                level.spawns.push(Spawn { head: pos, direction });
+            }
+        }
+        level.validate()?;
+        Ok(level)
+    }
+
+    fn set_header(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "name" => self.name = value.to_string(),
+            "edges" => self.edges = match value {
+                "wrap" => Edges::Wrap,
+                "solid" => Edges::Solid,
+                _ => anyhow::bail!("edges must be wrap or solid, got {value}"),
+            },
+            "length" => self.start_length = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("length expects a number, got {value}"))?,
+            _ => anyhow::bail!("Unknown level setting {key}"),
+        }
+        Ok(())
+    }
+
+    /// Loads a `.json` level, or a text map from any other file
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read level from {}", path.display()))?;
+        let level = if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_text(&contents)
+        };
+        let mut level = level.with_context(|| format!("Couldn't load level {}", path.display()))?;
+        if level.name.is_empty() {
+            level.name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        }
+        Ok(level)
+    }
+
+    /// The built-in level called `name`, or else the level file at that path
+    pub fn find(name: &str) -> anyhow::Result<Self> {
+        match BUILTIN_LEVELS.iter().find(|(builtin, _)| *builtin == name) {
+            Some((_, text)) => Self::from_text(text).with_context(|| format!("Built-in level {name} is broken")),
+            None => Self::load(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_text_reads_headers_and_map() {
+        let level = Level::from_text(
+            "; a comment\nname: Tiny\nedges: solid\nlength: 2\n\n######\n#O.<.#\n#.>*.#\n######\n\n",
+        ).unwrap();
+        assert_eq!(level.name, "Tiny");
+        assert_eq!(level.edges, Edges::Solid);
+        assert_eq!(level.start_length, 2);
+        assert_eq!(level.board, Board::new(6, 4));
+        // The top row of the map is the top of the board
+        assert_eq!(level.obstacles, [GridPos::new(1, 2)]);
+        assert_eq!(level.first_apple, Some(GridPos::new(3, 1)));
+        assert_eq!(level.spawns, [
+            Spawn { head: GridPos::new(3, 2), direction: SnakeInputs::Left },
+            Spawn { head: GridPos::new(2, 1), direction: SnakeInputs::Right },
+        ]);
+        assert_eq!(level.walls.len(), 16);
+        assert!(level.walls.contains(&GridPos::new(0, 3)) && level.walls.contains(&GridPos::new(5, 0)));
+    }
+
+    #[test]
+    fn from_text_rejects_bad_maps() {
+        for text in [
+            "",
+            "name: Empty\n",
+            "....\n...\n",
+            "..x.\n",
+            ".*..\n..*.\n",
+            "size: 4\n....\n",
+            "edges: round\n....\n",
+        ] {
+            assert!(Level::from_text(text).is_err(), "accepted {text:?}");
+        }
+    }
+
+    #[test]
+    fn builtin_levels_load() {
+        for (name, _) in BUILTIN_LEVELS {
+            Level::find(name).unwrap();
+        }
+    }
+}
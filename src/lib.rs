@@ -1,20 +1,39 @@
 pub mod r#struct;
+pub mod clock;
 pub mod math;
 pub mod camera;
+pub mod board3d;
+pub mod model;
 pub mod texture;
 pub mod snake;
+pub mod text;
+pub mod replay;
+pub mod config;
+pub mod level;
+pub mod controller;
+pub mod env;
+pub mod net;
+pub mod rollback;
+pub mod scores;
+
+#[cfg(not(target_arch="wasm32"))]
+pub mod server;
 
 #[cfg(target_arch="wasm32")] 
 pub mod wasm_lib;
 
 
 
-use r#struct::State;
+use std::path::PathBuf;
+
+use config::GameConfig;
+use replay::Replay;
+use r#struct::{Phase, State};
 use winit::{
-    dpi::LogicalSize, event::*, event_loop::{ControlFlow, EventLoop}, keyboard::{KeyCode, PhysicalKey}, window::WindowBuilder
+    dpi::LogicalSize, event::*, event_loop::EventLoop, keyboard::{KeyCode, PhysicalKey}, window::WindowBuilder
 };
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 pub enum SnakeInputs {
     Up,
     Down,
@@ -23,30 +42,82 @@ pub enum SnakeInputs {
     Stay
 }
 
+impl SnakeInputs {
+    pub fn opposite(self) -> Self {
+        match self {
+            SnakeInputs::Up => SnakeInputs::Down,
+            SnakeInputs::Down => SnakeInputs::Up,
+            SnakeInputs::Left => SnakeInputs::Right,
+            SnakeInputs::Right => SnakeInputs::Left,
+            SnakeInputs::Stay => SnakeInputs::Stay,
+        }
+    }
+}
+
  
 
  
 
 
-pub async fn run() {
+/// How the desktop game should start, filled in from the command line
+#[derive(Default)]
+pub struct RunOptions {
+    pub config: GameConfig,
+    /// Play this replay back instead of starting a new game
+    pub replay: Option<Replay>,
+    /// Save each finished game as a replay at this path
+    pub record_to: Option<PathBuf>,
+    /// Render one frame to this PNG without opening a window, then quit
+    pub screenshot: Option<PathBuf>,
+    /// Play on a server instead of locally
+    pub remote: Option<net::Connection>,
+}
+
+/// Renders a single frame offscreen and saves it as a PNG. With a replay the
+/// frame shows how it ends, otherwise the ready screen of a new game.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn screenshot(config: GameConfig, replay: Option<Replay>, path: &std::path::Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let mut state = State::new_headless(config).await?;
+    if let Some(replay) = replay {
+        state.play_replay(replay);
+        while state.phase == Phase::Playing {
+            state.tick();
+        }
+    }
+    state.render_to_image()?
+        .save(path)
+        .with_context(|| format!("Couldn't save screenshot to {}", path.display()))
+}
+
+pub async fn run(options: RunOptions) {
     env_logger::init();
     
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new()
     .with_title("Snake")
-    .with_inner_size(LogicalSize::new(800.0, 800.0))
+    .with_inner_size(LogicalSize::new(options.config.window_width, options.config.window_height))
     .with_resizable(false)
     .build(&event_loop).unwrap();
     window.set_resizable(false);
-    let mut state = State::new(&window).await;
-
+    let mut state = State::new(&window, options.config).await;
+    state.record_to = options.record_to;
+    if let Some(replay) = options.replay {
+        state.play_replay(replay);
+    }
+    if let Some(connection) = options.remote {
+        state.connect(connection);
+    }
+
+    let window = &window;
     event_loop.run(move |event, control_flow| {
         match event {
             
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == state.window().id() => if !state.input(event) {
+            } if window_id == window.id() && !state.input(event) => {
                 match event {
                 WindowEvent::CloseRequested
                 | WindowEvent::KeyboardInput {
@@ -59,7 +130,7 @@ pub async fn run() {
                     ..
                 } => control_flow.exit(),
                 WindowEvent::RedrawRequested => {
-                    state.window().request_redraw();
+                    window.request_redraw();
                     
         
                     state.update();
@@ -1,16 +1,187 @@
-#[cfg(target_arch="x86_64")]
-use rendering::run;
+#[cfg(not(target_arch="wasm32"))]
+use rendering::{config::GameConfig, level::{Level, BUILTIN_LEVELS}, net::{Connection, DEFAULT_PORT}, replay::Replay, run, screenshot, snake::Board, RunOptions};
 
 
 #[cfg(target_arch="wasm32")] 
 use rendering::wasm_lib::run;
 
 
+#[cfg(not(target_arch="wasm32"))]
+const USAGE: &str = "Usage: rendering [options]
 
+Options:
+    --config <file.json>    Load settings from a JSON file, flags below override it
+    --level <name|file>     Play a built-in level or a level file, flags below override it
+    --board <WxH>           Board size in cells
+    --tick-ms <ms>          Milliseconds between moves
+    --cell-size <units>     Width of a cell in world units
+    --window <WxH>          Window size in logical pixels
+    --start-length <n>      Segments each snake starts with
+    --players <n>           Snakes on one keyboard: WASD, arrows, IJKL, numpad 8456
+    --controllers <list>    Who steers each snake, comma separated: keyboard,
+                            greedy, pathfinder or hamiltonian
+    --seed <n>              Seed every game with this instead of a random one
+    --replay <file>         Play a recorded game back
+    --record <file>         Save each finished game as a replay
+    --screenshot <file.png> Render one frame without a window and quit
+    --3d                    Draw the board in 3D, V switches while playing
+    --connect <host[:port]> Join a game on a snake-server
+    --name <name>           Name for high scores and to join with, \"player\" by default
+    --latency <ms>          Fake this much network delay each way when online
+    --jitter <ms>           Vary the fake delay by up to this much
+    --loss <fraction>       Fake losing this share of lockstep turns";
 
 
+/// Parses `WxH` into its two halves
+#[cfg(not(target_arch="wasm32"))]
+fn parse_size<T: std::str::FromStr>(flag: &str, value: &str) -> anyhow::Result<(T, T)> {
+    let parse = |s: &str| s.trim().parse::<T>().ok();
+    value
+        .split_once(['x', 'X'])
+        .and_then(|(w, h)| Some((parse(w)?, parse(h)?)))
+        .ok_or_else(|| anyhow::anyhow!("{flag} expects a size like 20x20, got {value}"))
+}
+
+#[cfg(not(target_arch="wasm32"))]
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> anyhow::Result<T> {
+    value.parse().map_err(|_| anyhow::anyhow!("{flag} expects a number, got {value}"))
+}
+
+#[cfg(not(target_arch="wasm32"))]
+fn parse_args(args: impl Iterator<Item = String>) -> anyhow::Result<RunOptions> {
+    let args = args.collect::<Vec<_>>();
+    let mut options = RunOptions::default();
 
+    // The config file goes first so the other flags can override it wherever they appear
+    if let Some(i) = args.iter().position(|arg| arg == "--config") {
+        let path = args.get(i + 1).ok_or_else(|| anyhow::anyhow!("--config needs a file\n{USAGE}"))?;
+        options.config = GameConfig::load(path)?;
+    }
 
+    let mut connect = None;
+    // Applied once every flag is in, with the board flags over the top
+    let mut level = None;
+    let mut board = None;
+    let mut start_length = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        if arg == "--3d" {
+            options.config.three_d = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| match arg.as_str() {
+            "--level" => {
+                let builtin = BUILTIN_LEVELS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+                anyhow::anyhow!("--level needs a name ({builtin}) or a file\n{USAGE}")
+            },
+            _ => anyhow::anyhow!("{arg} needs a value\n{USAGE}"),
+        })?;
+        let config = &mut options.config;
+        match arg.as_str() {
+            "--config" => {},
+            "--level" => level = Some(Level::find(&value)?),
+            "--board" => {
+                let (width, height) = parse_size(&arg, &value)?;
+                board = Some(Board::new(width, height));
+            },
+            "--tick-ms" => config.tick_ms = parse_number(&arg, &value)?,
+            "--cell-size" => config.cell_size = parse_number(&arg, &value)?,
+            "--window" => (config.window_width, config.window_height) = parse_size(&arg, &value)?,
+            "--start-length" => start_length = Some(parse_number(&arg, &value)?),
+            "--players" => config.players = parse_number(&arg, &value)?,
+            "--controllers" => {
+                config.controllers = value.split(',').map(|name| name.trim().parse()).collect::<anyhow::Result<_>>()?;
+            },
+            "--seed" => config.seed = Some(parse_number(&arg, &value)?),
+            "--replay" => options.replay = Some(Replay::load(&value)?),
+            "--record" => options.record_to = Some(value.into()),
+            "--screenshot" => options.screenshot = Some(value.into()),
+            "--connect" => connect = Some(value),
+            "--name" => config.name = value,
+            "--latency" => config.network.latency_ms = parse_number(&arg, &value)?,
+            "--jitter" => config.network.jitter_ms = parse_number(&arg, &value)?,
+            "--loss" => config.network.loss = parse_number(&arg, &value)?,
+            _ => anyhow::bail!("Unknown argument {arg}\n{USAGE}"),
+        }
+    }
+    let config = &mut options.config;
+    if let Some(level) = level {
+        config.set_level(&level);
+    }
+    if let Some(board) = board {
+        config.board = board;
+    }
+    if let Some(start_length) = start_length {
+        config.start_length = start_length;
+    }
+    options.config.validate()?;
+    if let Some(mut address) = connect {
+        if !address.contains(':') {
+            address = format!("{address}:{DEFAULT_PORT}");
+        }
+        options.remote = Some(Connection::connect(address, &options.config.name)?);
+    }
+    Ok(options)
+}
+
+
+#[cfg(not(target_arch="wasm32"))]
+fn main() -> anyhow::Result<()> {
+    let mut options = parse_args(std::env::args().skip(1))?;
+    if let Some(path) = options.screenshot.take() {
+        return pollster::block_on(screenshot(options.config, options.replay, &path));
+    }
+    pollster::block_on(run(options));
+    Ok(())
+}
+
+#[cfg(target_arch="wasm32")]
 fn main() {
     pollster::block_on(run());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> GameConfig {
+        parse_args(args.iter().map(|arg| arg.to_string())).unwrap().config
+    }
+
+    #[test]
+    fn level_takes_the_player_count_wherever_it_is_given() {
+        let level = Level::find("box").unwrap();
+        for args in [["--level", "box", "--players", "2"], ["--players", "2", "--level", "box"]] {
+            let config = parse(&args);
+            assert_eq!(config.players, 2);
+            assert_eq!(config.rules(), level.rules(2));
+        }
+    }
+
+    #[test]
+    fn board_flags_override_the_level_wherever_they_are_given() {
+        let level = Level::find("box").unwrap();
+        for args in [["--start-length", "5", "--level", "box"], ["--level", "box", "--start-length", "5"]] {
+            let config = parse(&args);
+            assert_eq!(config.start_length, 5);
+            assert_eq!(config.walls, level.walls);
+        }
+    }
+
+    #[test]
+    fn flags_override_the_config_file_wherever_they_are_given() {
+        let path = std::env::temp_dir().join(format!("snake-config-test-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "tick_ms": 100, "board": { "width": 20, "height": 12 }, "players": 2 }"#).unwrap();
+        let path = path.to_str().unwrap();
+        for args in [["--tick-ms", "30", "--config", path], ["--config", path, "--tick-ms", "30"]] {
+            let config = parse(&args);
+            assert_eq!(config.tick_ms, 30);
+            assert_eq!((config.board, config.players), (Board::new(20, 12), 2));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3, Vector4};
+
+use crate::{
+    snake::{Material, Mesh, Model, ModelVertex, Sprite},
+    texture,
+};
+
+/// Model files drawn in 3D instead of the meshes made from the atlas, OBJ or
+/// glTF (`.gltf` or `.glb`) by extension.
+///
+/// Models are made y up, one unit to a cell, standing on the origin and
+/// facing +x the way the atlas sprites face. The corner joins the -x and -z
+/// sides, its tile's left and top. They keep their own materials, so every
+/// snake looks the same in them.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelPaths {
+    pub head: Option<PathBuf>,
+    pub body: Option<PathBuf>,
+    pub corner: Option<PathBuf>,
+    pub tail: Option<PathBuf>,
+    pub apple: Option<PathBuf>,
+}
+
+impl ModelPaths {
+    pub fn get(&self, sprite: Sprite) -> Option<&Path> {
+        match sprite {
+            Sprite::Head => self.head.as_deref(),
+            Sprite::Body => self.body.as_deref(),
+            Sprite::Corner => self.corner.as_deref(),
+            Sprite::Tail => self.tail.as_deref(),
+            Sprite::Apple => self.apple.as_deref(),
+            Sprite::Wall | Sprite::Obstacle => None,
+        }
+    }
+}
+
+/// Loads a model file with anything it refers to, such as `.mtl` files,
+/// textures and glTF buffers, from beside it. `layout` is the one the sprite
+/// atlas is bound with.
+pub fn load(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    path: &Path,
+    cell_size: f32,
+) -> anyhow::Result<Model> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let read = |name: &str| read_file(&dir.join(name));
+    let name = path.file_stem().map_or_else(|| "model".into(), |stem| stem.to_string_lossy());
+    let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase());
+    let model = match extension.as_deref() {
+        Some("obj") => read_file(path).and_then(|obj| from_obj(device, queue, layout, &name, &obj, read, cell_size)),
+        Some("gltf" | "glb") => read_file(path).and_then(|gltf| from_gltf(device, queue, layout, &name, &gltf, read, cell_size)),
+        _ => Err(anyhow::anyhow!("Models need to be .obj, .gltf or .glb files")),
+    };
+    model.with_context(|| format!("Couldn't load model {}", path.display()))
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            anyhow::bail!("Can't read {} in the browser", path.display())
+        } else {
+            std::fs::read(path).with_context(|| format!("Couldn't read {}", path.display()))
+        }
+    }
+}
+
+/// Builds a model from the text of an OBJ file. `read` fetches the files it
+/// names by their path in it.
+pub fn from_obj(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    name: &str,
+    obj: &[u8],
+    read: impl Fn(&str) -> anyhow::Result<Vec<u8>>,
+    cell_size: f32,
+) -> anyhow::Result<Model> {
+    let (models, materials) = tobj::load_obj_buf(&mut &obj[..], &tobj::GPU_LOAD_OPTIONS, |path| {
+        let mtl = read(&path.to_string_lossy()).map_err(|e| {
+            log::warn!("{e:#}");
+            tobj::LoadError::OpenFileFailed
+        })?;
+        tobj::load_mtl_buf(&mut &mtl[..])
+    })?;
+    // A missing material library leaves the meshes plain white
+    let materials = materials.unwrap_or_default();
+
+    let mut loaded = Vec::new();
+    for material in &materials {
+        let texture = match &material.diffuse_texture {
+            Some(file) => Some(image::load_from_memory(&read(file)?).with_context(|| format!("Couldn't decode {file}"))?.to_rgba8()),
+            None => None,
+        };
+        let [r, g, b] = material.diffuse.unwrap_or([1.0; 3]);
+        loaded.push(create_material(device, queue, layout, &material.name, texture, [r, g, b, 1.0])?);
+    }
+
+    let mut meshes = Vec::new();
+    for model in models {
+        let mesh = &model.mesh;
+        let normals = (mesh.normals.len() == mesh.positions.len()).then_some(&mesh.normals);
+        let mut vertices: Vec<ModelVertex> = (0..mesh.positions.len() / 3)
+            .map(|i| ModelVertex {
+                position: [mesh.positions[i * 3], mesh.positions[i * 3 + 1], mesh.positions[i * 3 + 2]],
+                // OBJ counts v up from the bottom of the texture
+                tex_coords: match mesh.texcoords.get(i * 2..i * 2 + 2) {
+                    Some(&[u, v]) => [u, 1.0 - v],
+                    _ => [0.0, 0.0],
+                },
+                normal: normals.map_or([0.0; 3], |normals| [normals[i * 3], normals[i * 3 + 1], normals[i * 3 + 2]]),
+            })
+            .collect();
+        if normals.is_none() {
+            smooth_normals(&mut vertices, &mesh.indices);
+        }
+        let material = mesh.material_id.filter(|&id| id < loaded.len());
+        meshes.push(create_mesh(device, &format!("{name} {}", model.name), vertices, &mesh.indices, material, cell_size));
+    }
+    finish(device, queue, layout, meshes, loaded)
+}
+
+/// Builds a model from a glTF file, JSON or binary. Its default scene is
+/// used, or its first if it has no default. `read` fetches the files it
+/// names by their URI in it.
+pub fn from_gltf(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    name: &str,
+    gltf: &[u8],
+    read: impl Fn(&str) -> anyhow::Result<Vec<u8>>,
+    cell_size: f32,
+) -> anyhow::Result<Model> {
+    let gltf = gltf::Gltf::from_slice(gltf)?;
+    let read_uri = |uri: &str| {
+        if uri.starts_with("data:") {
+            anyhow::bail!("Embedded data URIs aren't supported, export as .glb or with separate files");
+        }
+        read(uri)
+    };
+    let buffers = gltf
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone().context("The binary chunk is missing"),
+            gltf::buffer::Source::Uri(uri) => read_uri(uri),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut loaded = Vec::new();
+    for material in gltf.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let texture = match pbr.base_color_texture() {
+            Some(info) => {
+                let bytes = match info.texture().source().source() {
+                    gltf::image::Source::View { view, .. } => {
+                        let buffer = &buffers[view.buffer().index()];
+                        buffer.get(view.offset()..view.offset() + view.length()).context("An image is past the end of its buffer")?.to_vec()
+                    },
+                    gltf::image::Source::Uri { uri, .. } => read_uri(uri)?,
+                };
+                Some(image::load_from_memory(&bytes).context("Couldn't decode a texture")?.to_rgba8())
+            },
+            None => None,
+        };
+        let name = material.name().unwrap_or("material");
+        loaded.push(create_material(device, queue, layout, name, texture, pbr.base_color_factor())?);
+    }
+
+    let scene = gltf.default_scene().or_else(|| gltf.scenes().next()).context("There's no scene")?;
+    let mut meshes = Vec::new();
+    let mut nodes: Vec<_> = scene.nodes().map(|node| (node, Matrix4::identity())).collect();
+    while let Some((node, parent)) = nodes.pop() {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+        nodes.extend(node.children().map(|child| (child, transform)));
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!("Skipping part of {name} that isn't made of triangles");
+                continue;
+            }
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+            let positions: Vec<[f32; 3]> = reader.read_positions().context("A mesh has no positions")?.collect();
+            let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(Iterator::collect);
+            let mut tex_coords = reader.read_tex_coords(0).map(|coords| coords.into_f32());
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            let mut vertices: Vec<ModelVertex> = positions
+                .iter()
+                .enumerate()
+                .map(|(i, &[x, y, z])| {
+                    let normal = normals.as_ref().map_or(Vector3::new(0.0, 0.0, 0.0), |normals| normals[i].into());
+                    ModelVertex {
+                        position: (transform * Vector4::new(x, y, z, 1.0)).truncate().into(),
+                        tex_coords: tex_coords.as_mut().and_then(Iterator::next).unwrap_or([0.0, 0.0]),
+                        // Close enough unless the node is squashed along one axis
+                        normal: (transform * normal.extend(0.0)).truncate().normalize().into(),
+                    }
+                })
+                .collect();
+            if normals.is_none() {
+                smooth_normals(&mut vertices, &indices);
+            }
+            let material = primitive.material().index();
+            let mesh_name = format!("{name} {}", mesh.name().unwrap_or("mesh"));
+            meshes.push(create_mesh(device, &mesh_name, vertices, &indices, material, cell_size));
+        }
+    }
+    finish(device, queue, layout, meshes, loaded)
+}
+
+/// Gives meshes without a material a plain white one
+fn finish(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    meshes: Vec<(Mesh, Option<usize>)>,
+    mut materials: Vec<Material>,
+) -> anyhow::Result<Model> {
+    if meshes.is_empty() {
+        anyhow::bail!("There are no meshes in it");
+    }
+    if meshes.iter().any(|(_, material)| material.is_none()) {
+        materials.push(create_material(device, queue, layout, "default", None, [1.0; 4])?);
+    }
+    let default = materials.len() - 1;
+    Ok(Model {
+        meshes: meshes.into_iter().map(|(mesh, material)| Mesh { material: material.unwrap_or(default), ..mesh }).collect(),
+        materials,
+    })
+}
+
+/// `texture` tinted by `color`, or just `color` if there's no texture
+fn create_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    name: &str,
+    texture: Option<image::RgbaImage>,
+    color: [f32; 4],
+) -> anyhow::Result<Material> {
+    let mut texture = texture.unwrap_or_else(|| image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+    for pixel in texture.pixels_mut() {
+        for (channel, tint) in pixel.0.iter_mut().zip(color) {
+            *channel = (*channel as f32 * tint).round() as u8;
+        }
+    }
+    let diffuse_texture = texture::Texture::from_image(device, queue, &image::DynamicImage::ImageRgba8(texture), Some(name))?;
+    Ok(Material::new(device, layout, name, diffuse_texture))
+}
+
+/// Turns y up vertices in cells into the board's z up world units
+fn create_mesh(
+    device: &wgpu::Device,
+    name: &str,
+    mut vertices: Vec<ModelVertex>,
+    indices: &[u32],
+    material: Option<usize>,
+    cell_size: f32,
+) -> (Mesh, Option<usize>) {
+    for vertex in &mut vertices {
+        let [x, y, z] = vertex.position;
+        vertex.position = [x * cell_size, -z * cell_size, y * cell_size];
+        let [x, y, z] = vertex.normal;
+        vertex.normal = [x, -z, y];
+    }
+    (Mesh::new(device, name, &vertices, indices), material)
+}
+
+/// Normals for meshes that came without, averaged from the faces round each
+/// vertex
+fn smooth_normals(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut normals = vec![Vector3::new(0.0, 0.0, 0.0); vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(vertices[triangle[i] as usize].position));
+        // Left this long so bigger faces count for more
+        let face = (b - a).cross(c - a);
+        for &i in triangle {
+            normals[i as usize] += face;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        vertex.normal = if normal.magnitude2() > 0.0 { normal.normalize().into() } else { [0.0, 1.0, 0.0] };
+    }
+}
@@ -0,0 +1,386 @@
+use std::{collections::VecDeque, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    clock::Instant,
+    rollback::{Netcode, Rollback},
+    snake::{random_seed, GridPos, Rules, Snake, SnakeState},
+    SnakeInputs,
+};
+
+/// Port the server listens on unless told otherwise
+pub const DEFAULT_PORT: u16 = 7878;
+/// Port the server takes WebSocket players on, which is how the browser build joins
+pub const DEFAULT_WEBSOCKET_PORT: u16 = 7879;
+
+/// Sent by a client to the server. Both sides write one JSON message per
+/// line, or per text message over a WebSocket. Clients send `Signal`s and
+/// the server answers with `Update`s.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum Signal {
+    /// Asks for a snake in the next game
+    Join { name: String },
+    /// Turns the client's snake on the server's next tick
+    Input { direction: SnakeInputs },
+    /// The client's turns for the ticks from `tick` on in a lockstep game.
+    /// The latest few are sent every tick, so a lost message costs nothing.
+    Turns { tick: u32, turns: Vec<Option<SnakeInputs>> },
+}
+
+/// Sent by the server to its clients
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum Update {
+    /// Answers a join, `player` is `None` when every snake is taken and the
+    /// client can only watch
+    Welcome { player: Option<usize>, rules: Rules, tick_ms: u64 },
+    /// Players are still missing before the game can start
+    Waiting { joined: usize, needed: usize },
+    Snapshot(Snapshot),
+    /// A lockstep game begins, every player runs it from `seed` themselves
+    /// and only turns are passed around. Spectators keep getting snapshots.
+    Start { seed: u64, netcode: Netcode },
+    /// Another player's turns in a lockstep game, passed on as sent
+    Turns { player: usize, tick: u32, turns: Vec<Option<SnakeInputs>> },
+    /// Sent once when a game ends, the next starts on its own shortly after
+    GameOver { winner: Option<usize>, scores: Vec<u32> },
+    /// The request could not be handled
+    Rejected { reason: String },
+}
+
+/// Everything that changes during a game, the rules stay as sent in `Welcome`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub ticks: u32,
+    pub snakes: Vec<Snake>,
+    pub apple: Option<GridPos>,
+    pub ended: bool,
+    pub won: bool,
+    pub winner: Option<usize>,
+}
+
+impl Snapshot {
+    pub fn of(game: &SnakeState) -> Self {
+        Self {
+            ticks: game.ticks,
+            snakes: game.snakes.clone(),
+            apple: game.apple,
+            ended: game.ended,
+            won: game.won,
+            winner: game.winner,
+        }
+    }
+
+    /// Overwrites `game` with this snapshot, `game` must be using the same rules
+    pub fn apply(self, game: &mut SnakeState) {
+        game.ticks = self.ticks;
+        game.snakes = self.snakes;
+        game.apple = self.apple;
+        game.ended = self.ended;
+        game.won = self.won;
+        game.winner = self.winner;
+    }
+}
+
+/// Encodes a message as one protocol line, newline included
+pub fn encode(message: &impl serde::Serialize) -> anyhow::Result<String> {
+    Ok(serde_json::to_string(message)? + "\n")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::Connection;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpStream, ToSocketAddrs},
+        sync::mpsc::{self, Receiver, TryRecvError},
+    };
+
+    use anyhow::Context;
+
+    use super::{encode, Signal, Update};
+
+    /// A client's link to a server. Updates are read on a background thread
+    /// and picked up with `poll` once a frame.
+    pub struct Connection {
+        writer: TcpStream,
+        updates: Receiver<Update>,
+    }
+
+    impl Connection {
+        /// Connects to `address` and asks to join as `name`
+        pub fn connect(address: impl ToSocketAddrs, name: &str) -> anyhow::Result<Self> {
+            let writer = TcpStream::connect(address).context("Couldn't connect to the server")?;
+            writer.set_nodelay(true)?;
+            let reader = BufReader::new(writer.try_clone()?);
+            let (sender, updates) = mpsc::channel();
+            std::thread::spawn(move || {
+                for line in reader.lines() {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    match serde_json::from_str(&line) {
+                        Ok(update) => {
+                            if sender.send(update).is_err() {
+                                break;
+                            }
+                        },
+                        Err(e) => log::warn!("Ignoring a bad message from the server: {e}"),
+                    }
+                }
+            });
+            let mut connection = Self { writer, updates };
+            connection.send(&Signal::Join { name: name.to_string() })?;
+            Ok(connection)
+        }
+
+        pub fn send(&mut self, signal: &Signal) -> anyhow::Result<()> {
+            self.writer.write_all(encode(signal)?.as_bytes()).context("Lost the connection to the server")
+        }
+
+        /// Every update received since the last poll, or an error once the
+        /// server has gone away
+        pub fn poll(&mut self) -> anyhow::Result<Vec<Update>> {
+            let mut updates = Vec::new();
+            loop {
+                match self.updates.try_recv() {
+                    Ok(update) => updates.push(update),
+                    Err(TryRecvError::Empty) => return Ok(updates),
+                    Err(TryRecvError::Disconnected) if updates.is_empty() => {
+                        anyhow::bail!("The server closed the connection")
+                    },
+                    Err(TryRecvError::Disconnected) => return Ok(updates),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use websocket::Connection;
+
+#[cfg(target_arch = "wasm32")]
+mod websocket {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+    use web_sys::{MessageEvent, WebSocket};
+
+    use super::{encode, Signal, Update};
+
+    /// Filled in by the socket's callbacks, emptied by `poll`
+    #[derive(Default)]
+    struct Inbox {
+        updates: VecDeque<Update>,
+        closed: bool,
+    }
+
+    /// A browser's link to a server over a WebSocket, same as the native
+    /// `Connection` but fed by the page's event loop instead of a thread
+    pub struct Connection {
+        socket: WebSocket,
+        inbox: Rc<RefCell<Inbox>>,
+        /// The socket only holds on to these through JS, so they live here
+        _on_message: Closure<dyn FnMut(MessageEvent)>,
+        _on_open: Closure<dyn FnMut(JsValue)>,
+        _on_close: Closure<dyn FnMut(JsValue)>,
+    }
+
+    impl Connection {
+        /// Opens a WebSocket to `url`, a `ws://` or `wss://` address, and
+        /// asks to join as `name` once it is open
+        pub fn connect(url: &str, name: &str) -> anyhow::Result<Self> {
+            let socket = WebSocket::new(url).map_err(|e| anyhow::anyhow!("Couldn't connect to {url}: {e:?}"))?;
+            let inbox = Rc::new(RefCell::new(Inbox::default()));
+
+            let on_message = {
+                let inbox = inbox.clone();
+                Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                    let Some(text) = event.data().as_string() else {
+                        return;
+                    };
+                    match serde_json::from_str(&text) {
+                        Ok(update) => inbox.borrow_mut().updates.push_back(update),
+                        Err(e) => log::warn!("Ignoring a bad message from the server: {e}"),
+                    }
+                })
+            };
+            let on_open = {
+                let socket = socket.clone();
+                let join = encode(&Signal::Join { name: name.to_string() })?;
+                Closure::<dyn FnMut(JsValue)>::new(move |_| {
+                    if let Err(e) = socket.send_with_str(join.trim_end()) {
+                        log::error!("Couldn't join the server: {e:?}");
+                    }
+                })
+            };
+            let on_close = {
+                let inbox = inbox.clone();
+                Closure::<dyn FnMut(JsValue)>::new(move |_| inbox.borrow_mut().closed = true)
+            };
+            socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+            socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+            socket.set_onerror(Some(on_close.as_ref().unchecked_ref()));
+
+            Ok(Self { socket, inbox, _on_message: on_message, _on_open: on_open, _on_close: on_close })
+        }
+
+        /// Sends `signal`, dropping it if the socket hasn't opened yet
+        pub fn send(&mut self, signal: &Signal) -> anyhow::Result<()> {
+            if self.socket.ready_state() != WebSocket::OPEN {
+                return Ok(());
+            }
+            self.socket
+                .send_with_str(encode(signal)?.trim_end())
+                .map_err(|e| anyhow::anyhow!("Lost the connection to the server: {e:?}"))
+        }
+
+        /// Every update received since the last poll, or an error once the
+        /// server has gone away
+        pub fn poll(&mut self) -> anyhow::Result<Vec<Update>> {
+            let mut inbox = self.inbox.borrow_mut();
+            if inbox.updates.is_empty() && inbox.closed {
+                anyhow::bail!("The server closed the connection");
+            }
+            Ok(inbox.updates.drain(..).collect())
+        }
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            // The callbacks are freed with us, JS mustn't call them afterwards
+            self.socket.set_onmessage(None);
+            self.socket.set_onopen(None);
+            self.socket.set_onclose(None);
+            self.socket.set_onerror(None);
+            let _ = self.socket.close();
+        }
+    }
+}
+
+/// Network trouble to fake on top of a real connection, for trying out the
+/// netcode without leaving the machine
+#[derive(PartialEq, Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConditions {
+    /// Added to every message, each way
+    pub latency_ms: u64,
+    /// Up to this much more or less than `latency_ms`, picked per message
+    pub jitter_ms: u64,
+    /// Chance of losing a message, from 0 to 1. Only lockstep turns are ever
+    /// lost, they are resent anyway and the rest of the protocol isn't.
+    pub loss: f32,
+}
+
+impl NetworkConditions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            anyhow::bail!("Loss must be between 0 and 1, got {}", self.loss);
+        }
+        if self.latency_ms > 10_000 || self.jitter_ms > 10_000 {
+            anyhow::bail!("Latency and jitter must be at most 10000 ms");
+        }
+        Ok(())
+    }
+
+    pub fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Holds messages back for as long as `NetworkConditions` say. Order is kept,
+/// like over TCP, so a slow message holds up the ones behind it.
+pub struct DelayLine<T> {
+    pub conditions: NetworkConditions,
+    queue: VecDeque<(Instant, T)>,
+    rng: StdRng,
+}
+
+impl<T> DelayLine<T> {
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        Self { conditions, queue: VecDeque::new(), rng: StdRng::seed_from_u64(seed) }
+    }
+
+    /// Sends `message` down the line at `now`, losing it now and then if it
+    /// is `droppable`
+    pub fn push(&mut self, message: T, droppable: bool, now: Instant) {
+        if droppable && self.rng.gen::<f32>() < self.conditions.loss {
+            return;
+        }
+        let jitter = self.conditions.jitter_ms as i64;
+        let delay = self.conditions.latency_ms as i64 + self.rng.gen_range(-jitter, jitter + 1);
+        let mut arrival = now + Duration::from_millis(delay.max(0) as u64);
+        if let Some(&(last, _)) = self.queue.back() {
+            arrival = arrival.max(last);
+        }
+        self.queue.push_back((arrival, message));
+    }
+
+    /// Messages that have arrived by `now`
+    pub fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        while let Some(&(arrival, _)) = self.queue.front() {
+            if arrival > now {
+                break;
+            }
+            due.extend(self.queue.pop_front().map(|(_, message)| message));
+        }
+        due
+    }
+}
+
+/// A game followed over the network rather than simulated locally
+pub struct Remote {
+    pub connection: Connection,
+    /// The snake this client steers, `None` while unknown or when watching
+    pub player: Option<usize>,
+    /// Players joined and needed while the server waits to start
+    pub waiting: Option<(usize, usize)>,
+    /// The server has gone away
+    pub closed: bool,
+    /// The lockstep game being played, `None` when following snapshots
+    pub rollback: Option<Rollback>,
+    /// Messages out and in, held back when faking a bad network
+    link: Option<(DelayLine<Signal>, DelayLine<Update>)>,
+}
+
+impl Remote {
+    pub fn new(connection: Connection, conditions: NetworkConditions) -> Self {
+        let link = (!conditions.is_perfect())
+            .then(|| (DelayLine::new(conditions, random_seed()), DelayLine::new(conditions, random_seed())));
+        Self { connection, player: None, waiting: None, closed: false, rollback: None, link }
+    }
+
+    pub fn send(&mut self, signal: Signal) -> anyhow::Result<()> {
+        match &mut self.link {
+            Some((outgoing, _)) => {
+                let droppable = matches!(signal, Signal::Turns { .. });
+                outgoing.push(signal, droppable, Instant::now());
+                Ok(())
+            },
+            None => self.connection.send(&signal),
+        }
+    }
+
+    /// Every update that arrived since the last poll, see `Connection::poll`.
+    /// Also sends whatever the fake network has let through by now.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<Update>> {
+        let Some((outgoing, incoming)) = &mut self.link else {
+            return self.connection.poll();
+        };
+        let now = Instant::now();
+        for signal in outgoing.pop_due(now) {
+            self.connection.send(&signal)?;
+        }
+        for update in self.connection.poll()? {
+            let droppable = matches!(update, Update::Turns { .. });
+            incoming.push(update, droppable, now);
+        }
+        Ok(incoming.pop_due(now))
+    }
+}
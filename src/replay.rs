@@ -0,0 +1,192 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::{snake::{Rules, SnakeState, StepOutcome}, SnakeInputs};
+
+/// Bumped whenever a change to the game rules or this format would make old
+/// replays play out differently
+pub const REPLAY_VERSION: u32 = 3;
+
+/// Everything needed to play a game again exactly: the seed, the rules and
+/// the turns made on each tick.
+///
+/// Tick 0 is the first move after the game leaves the ready screen. Only
+/// turns actually made are stored, as `(tick, player, turn)` in tick order.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    pub rules: Rules,
+    /// Number of ticks played
+    pub ticks: u32,
+    pub inputs: Vec<(u32, usize, SnakeInputs)>,
+}
+
+impl Replay {
+    /// An empty recording for a game started from `seed` under `rules`
+    pub fn new(seed: u64, rules: Rules) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            seed,
+            rules,
+            ticks: 0,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Appends one tick, with the turns handed to the game on it
+    pub fn record(&mut self, inputs: &[Option<SnakeInputs>]) {
+        for (player, input) in inputs.iter().enumerate() {
+            if let Some(input) = *input {
+                self.inputs.push((self.ticks, player, input));
+            }
+        }
+        self.ticks += 1;
+    }
+
+    /// A fresh game set up the way the recorded one started
+    pub fn new_game(&self) -> SnakeState {
+        SnakeState::new(self.rules.clone(), self.seed)
+    }
+
+    /// Plays the whole replay without rendering and returns the final state
+    pub fn play(&self) -> SnakeState {
+        let mut game = self.new_game();
+        game.start();
+        let mut player = ReplayPlayer::new(self.clone());
+        while let Some(inputs) = player.next_input() {
+            if matches!(game.step(&inputs), StepOutcome::Died | StepOutcome::Won) {
+                break;
+            }
+        }
+        game
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let replay: Self = serde_json::from_str(json).context("Replay is not valid JSON")?;
+        if replay.version != REPLAY_VERSION {
+            anyhow::bail!(
+                "Replay was recorded with format version {}, this build plays version {}",
+                replay.version,
+                REPLAY_VERSION
+            );
+        }
+        replay.rules.validate().context("Replay has invalid rules")?;
+        if let Some(&(tick, player, _)) = replay.inputs.iter().find(|&&(_, player, _)| player >= replay.rules.players) {
+            anyhow::bail!("Replay has a turn for player {} on tick {tick} of a {} player game", player + 1, replay.rules.players);
+        }
+        // Played back in one pass, so a turn out of place would hold up every one after it
+        let mut last = 0;
+        for &(tick, player, _) in &replay.inputs {
+            if tick < last {
+                anyhow::bail!("Replay has a turn for player {} on tick {tick} after one on tick {last}", player + 1);
+            }
+            if tick >= replay.ticks {
+                anyhow::bail!("Replay has a turn for player {} on tick {tick} of a {} tick game", player + 1, replay.ticks);
+            }
+            last = tick;
+        }
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?)
+            .with_context(|| format!("Couldn't write replay to {}", path.display()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read replay from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("Couldn't load replay {}", path.display()))
+    }
+}
+
+/// Hands out the turns of a replay one tick at a time
+pub struct ReplayPlayer {
+    pub replay: Replay,
+    tick: u32,
+    next: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self { replay, tick: 0, next: 0 }
+    }
+
+    /// Each player's turn on the next tick, or `None` once every recorded
+    /// tick has been played
+    pub fn next_input(&mut self) -> Option<Vec<Option<SnakeInputs>>> {
+        if self.tick >= self.replay.ticks {
+            return None;
+        }
+        let mut inputs = vec![None; self.replay.rules.players];
+        while let Some(&(tick, player, input)) = self.replay.inputs.get(self.next) {
+            if tick != self.tick {
+                break;
+            }
+            inputs[player] = Some(input);
+            self.next += 1;
+        }
+        self.tick += 1;
+        Some(inputs)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tick >= self.replay.ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays a game that turns every few ticks, recording it as it goes
+    fn recorded_game() -> (Replay, SnakeState) {
+        let rules = Rules::default();
+        let mut replay = Replay::new(7, rules.clone());
+        let mut game = SnakeState::new(rules, 7);
+        game.start();
+        let turns = [SnakeInputs::Up, SnakeInputs::Left, SnakeInputs::Down, SnakeInputs::Left];
+        for tick in 0..200 {
+            let turn = (tick % 5 == 0).then(|| turns[tick / 5 % turns.len()]);
+            replay.record(&[turn]);
+            if matches!(game.step(&[turn]), StepOutcome::Died | StepOutcome::Won) {
+                break;
+            }
+        }
+        (replay, game)
+    }
+
+    #[test]
+    fn playback_reaches_the_recorded_end() {
+        let (replay, game) = recorded_game();
+        let loaded = Replay::from_json(&replay.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, replay);
+        let played = loaded.play();
+        assert_eq!(played.snakes, game.snakes);
+        assert_eq!(played.apple, game.apple);
+        assert_eq!(played.ticks, game.ticks);
+        assert_eq!(played.ended, game.ended);
+    }
+
+    #[test]
+    fn from_json_rejects_misplaced_turns() {
+        let (replay, _) = recorded_game();
+        let mut out_of_order = replay.clone();
+        out_of_order.inputs.swap(0, 1);
+        assert!(Replay::from_json(&out_of_order.to_json().unwrap()).is_err());
+        let mut past_the_end = replay.clone();
+        past_the_end.inputs.push((replay.ticks, 0, SnakeInputs::Up));
+        assert!(Replay::from_json(&past_the_end.to_json().unwrap()).is_err());
+        let mut other_player = replay;
+        other_player.inputs[0].1 = 1;
+        assert!(Replay::from_json(&other_player.to_json().unwrap()).is_err());
+    }
+}
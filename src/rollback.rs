@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{snake::SnakeState, SnakeInputs};
+
+/// Local turns sent again with every new one, so a lost message doesn't
+/// leave the others waiting
+pub const RESEND_TURNS: usize = 8;
+
+/// How a lockstep game trades responsiveness against corrections
+#[derive(PartialEq, Eq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Netcode {
+    /// Ticks between a key press and the turn happening, gives the other
+    /// players' turns time to arrive before they are needed
+    pub input_delay: u32,
+    /// Ticks the game may run ahead of the last one with every turn in,
+    /// guessing the missing ones. 0 waits for every turn, plain lockstep.
+    pub max_prediction: u32,
+}
+
+impl Default for Netcode {
+    fn default() -> Self {
+        Self { input_delay: 2, max_prediction: 8 }
+    }
+}
+
+impl Netcode {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.input_delay > 30 {
+            anyhow::bail!("Input delay must be at most 30 ticks, got {}", self.input_delay);
+        }
+        if self.max_prediction > 60 {
+            anyhow::bail!("Prediction must be at most 60 ticks, got {}", self.max_prediction);
+        }
+        Ok(())
+    }
+}
+
+/// A game every peer simulates itself from everyone's turns.
+///
+/// Each tick, every player sends the turn (or `None`) they make `input_delay`
+/// ticks later. Ticks with every turn in are confirmed. Past that the game is
+/// predicted, guessing that nobody turns, and when a turn arrives that the
+/// guess got wrong, the prediction is thrown away and replayed from the last
+/// confirmed tick.
+pub struct Rollback {
+    pub netcode: Netcode,
+    /// The player steered here, `None` on the server which only confirms
+    pub local: Option<usize>,
+    /// The game after `confirmed_tick` ticks
+    confirmed: SnakeState,
+    confirmed_tick: u32,
+    /// `confirmed` carried on to `tick` with guessed turns, what is shown
+    predicted: SnakeState,
+    tick: u32,
+    /// Turns known from `confirmed_tick` on, one slot per player
+    turns: BTreeMap<u32, Vec<Option<Option<SnakeInputs>>>>,
+    /// The last `RESEND_TURNS` local turns and the ticks they are for
+    recent: VecDeque<(u32, Option<SnakeInputs>)>,
+    /// Times a wrong guess had to be replayed, for showing how it's going
+    pub rollbacks: u32,
+}
+
+impl Rollback {
+    /// Takes over `game`, which should already be started
+    pub fn new(game: SnakeState, local: Option<usize>, netcode: Netcode) -> Self {
+        Self {
+            netcode,
+            local,
+            predicted: game.clone(),
+            confirmed: game,
+            confirmed_tick: 0,
+            tick: 0,
+            turns: BTreeMap::new(),
+            recent: VecDeque::new(),
+            rollbacks: 0,
+        }
+    }
+
+    /// The game as it should be shown
+    pub fn game(&self) -> &SnakeState {
+        &self.predicted
+    }
+
+    /// The game as far as everyone's turns are known
+    pub fn confirmed(&self) -> &SnakeState {
+        &self.confirmed
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn confirmed_tick(&self) -> u32 {
+        self.confirmed_tick
+    }
+
+    /// `player`'s turn on `tick` if it is known. Nobody turns during the
+    /// first `input_delay` ticks, there was no time to.
+    fn turn(&self, tick: u32, player: usize) -> Option<Option<SnakeInputs>> {
+        if tick < self.netcode.input_delay {
+            return Some(None);
+        }
+        self.turns.get(&tick).and_then(|turns| turns[player])
+    }
+
+    fn players(&self) -> usize {
+        self.confirmed.snakes.len()
+    }
+
+    /// Whether every player's turn on `tick` is in, leaving out the local one
+    fn others_known(&self, tick: u32) -> bool {
+        (0..self.players()).all(|player| Some(player) == self.local || self.turn(tick, player).is_some())
+    }
+
+    /// Turns for `tick`, guessing that nobody turns where they aren't known
+    fn guess(&self, tick: u32) -> Vec<Option<SnakeInputs>> {
+        (0..self.players()).map(|player| self.turn(tick, player).flatten()).collect()
+    }
+
+    /// The first tick `player`'s turn is missing for
+    pub fn next_unknown(&self, player: usize) -> u32 {
+        let mut tick = self.confirmed_tick;
+        while self.turn(tick, player).is_some() {
+            tick += 1;
+        }
+        tick
+    }
+
+    /// Whether `advance` may run, or the game is too far ahead of the turns
+    /// coming in and has to wait for them
+    pub fn can_advance(&self) -> bool {
+        self.tick < self.confirmed_tick + self.netcode.max_prediction || self.others_known(self.tick)
+    }
+
+    /// Plays the next tick, scheduling the local player's `turn` for
+    /// `input_delay` ticks later. Check `can_advance` first.
+    pub fn advance(&mut self, turn: Option<SnakeInputs>) {
+        if let Some(local) = self.local {
+            let tick = self.tick + self.netcode.input_delay;
+            self.set_turn(tick, local, turn);
+            self.recent.push_back((tick, turn));
+            if self.recent.len() > RESEND_TURNS {
+                self.recent.pop_front();
+            }
+        }
+        let turns = self.guess(self.tick);
+        self.predicted.step(&turns);
+        self.tick += 1;
+        self.confirm();
+    }
+
+    /// The local turns worth sending after an `advance`: the tick of the
+    /// first, and the latest few in order
+    pub fn recent_turns(&self) -> (u32, Vec<Option<SnakeInputs>>) {
+        let first = self.recent.front().map_or(0, |&(tick, _)| tick);
+        (first, self.recent.iter().map(|&(_, turn)| turn).collect())
+    }
+
+    /// Records `player`'s turns from `tick` on, replaying the prediction if
+    /// it guessed any of them wrong. Turns already known are skipped.
+    pub fn receive(&mut self, player: usize, tick: u32, turns: &[Option<SnakeInputs>]) {
+        if player >= self.players() || Some(player) == self.local {
+            return;
+        }
+        let mut mispredicted = false;
+        for (tick, &turn) in (tick..).zip(turns) {
+            if tick < self.confirmed_tick || self.turn(tick, player).is_some() {
+                continue;
+            }
+            self.set_turn(tick, player, turn);
+            // Everything missing was guessed as no turn
+            mispredicted |= tick < self.tick && turn.is_some();
+        }
+        self.confirm();
+        if mispredicted {
+            self.rollbacks += 1;
+            self.predicted = self.confirmed.clone();
+            for tick in self.confirmed_tick..self.tick {
+                let turns = self.guess(tick);
+                self.predicted.step(&turns);
+            }
+        }
+    }
+
+    fn set_turn(&mut self, tick: u32, player: usize, turn: Option<SnakeInputs>) {
+        let players = self.players();
+        self.turns.entry(tick).or_insert_with(|| vec![None; players])[player] = Some(turn);
+    }
+
+    /// Steps the confirmed game over every tick with all turns in. Clients
+    /// stop at the predicted tick, the server has no prediction and follows
+    /// the confirmed game.
+    fn confirm(&mut self) {
+        while (self.local.is_none() || self.confirmed_tick < self.tick)
+            && (0..self.players()).all(|player| self.turn(self.confirmed_tick, player).is_some())
+        {
+            let turns = self.guess(self.confirmed_tick);
+            self.confirmed.step(&turns);
+            self.turns.remove(&self.confirmed_tick);
+            self.confirmed_tick += 1;
+        }
+        if self.confirmed_tick > self.tick {
+            self.tick = self.confirmed_tick;
+            self.predicted = self.confirmed.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{clock::Instant, net::{DelayLine, NetworkConditions}, snake::Rules};
+
+    const TICKS: u32 = 120;
+
+    /// Each player drives round a small square, turning every fourth tick
+    fn script(player: usize, tick: u32) -> Option<SnakeInputs> {
+        let squares = [
+            [SnakeInputs::Down, SnakeInputs::Right, SnakeInputs::Up, SnakeInputs::Left],
+            [SnakeInputs::Up, SnakeInputs::Left, SnakeInputs::Down, SnakeInputs::Right],
+        ];
+        (tick % 4 == 3).then(|| squares[player][(tick / 4) as usize % 4])
+    }
+
+    fn rules() -> Rules {
+        Rules { players: 2, ..Rules::default() }
+    }
+
+    #[test]
+    fn peers_roll_back_to_the_same_game() {
+        let netcode = Netcode { input_delay: 1, max_prediction: 8 };
+        let conditions = NetworkConditions { latency_ms: 80, jitter_ms: 40, loss: 0.0 };
+        let mut peers = (0..2)
+            .map(|player| {
+                let mut game = SnakeState::new(rules(), 3);
+                game.start();
+                Rollback::new(game, Some(player), netcode)
+            })
+            .collect::<Vec<_>>();
+        // links[i] carries player i's turns to the other peer
+        let mut links = (0..2)
+            .map(|player| DelayLine::<(u32, Vec<Option<SnakeInputs>>)>::new(conditions, player))
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+        let mut frame = 0;
+        while peers.iter().any(|peer| peer.confirmed_tick() < TICKS) {
+            frame += 1;
+            assert!(frame < 10 * TICKS, "peers stopped confirming ticks");
+            let now = start + Duration::from_millis(16 * u64::from(frame));
+            for player in 0..2 {
+                let other = 1 - player;
+                for (tick, turns) in links[other].pop_due(now) {
+                    peers[player].receive(other, tick, &turns);
+                }
+                let peer = &mut peers[player];
+                if peer.tick() < TICKS && peer.can_advance() {
+                    peer.advance(script(player, peer.tick()));
+                    links[player].push(peer.recent_turns(), true, now);
+                }
+            }
+        }
+
+        // Turns take effect `input_delay` ticks after they are made
+        let mut expected = SnakeState::new(rules(), 3);
+        expected.start();
+        for tick in 0..TICKS {
+            let turns = (0..2)
+                .map(|player| tick.checked_sub(netcode.input_delay).and_then(|made| script(player, made)))
+                .collect::<Vec<_>>();
+            expected.step(&turns);
+        }
+        assert!(peers.iter().map(|peer| peer.rollbacks).sum::<u32>() > 0, "no turn was ever guessed wrong");
+        for peer in &peers {
+            for game in [peer.confirmed(), peer.game()] {
+                assert_eq!(game.snakes, expected.snakes);
+                assert_eq!(game.apple, expected.apple);
+                assert_eq!(game.ticks, expected.ticks);
+            }
+        }
+        assert!(expected.snakes.iter().all(|snake| snake.alive));
+    }
+
+    #[test]
+    fn prediction_stops_at_max_prediction() {
+        let netcode = Netcode { input_delay: 0, max_prediction: 4 };
+        let mut game = SnakeState::new(rules(), 3);
+        game.start();
+        let mut peer = Rollback::new(game, Some(0), netcode);
+        while peer.can_advance() {
+            peer.advance(None);
+        }
+        assert_eq!((peer.tick(), peer.confirmed_tick()), (4, 0));
+        peer.receive(1, 0, &[None, Some(SnakeInputs::Up)]);
+        assert_eq!(peer.confirmed_tick(), 2);
+        assert_eq!(peer.rollbacks, 1);
+        assert!(peer.can_advance());
+    }
+}
@@ -0,0 +1,362 @@
+use crate::{level::{Level, BUILTIN_LEVELS}, snake::{Board, Edges, GridPos, Rules}};
+
+/// Scores kept, lower ones fall off the table
+pub const MAX_HIGH_SCORES: usize = 10;
+
+/// Bumped when a change to `HighScore` would make old tables unreadable
+pub const SCORES_VERSION: u32 = 1;
+
+/// Who else was on the board
+#[derive(PartialEq, Eq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Mode {
+    Solo,
+    /// Against other snakes, this many in all
+    Versus(usize),
+}
+
+impl Mode {
+    pub fn name(self) -> String {
+        match self {
+            Mode::Solo => "SOLO".to_string(),
+            Mode::Versus(players) => format!("{players}P"),
+        }
+    }
+}
+
+/// One finished game on the table
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HighScore {
+    pub name: String,
+    pub score: u32,
+    /// Seconds since the Unix epoch when the game ended
+    pub date: u64,
+    /// Playing the seed again with `--seed` gives the same apples
+    pub seed: u64,
+    pub mode: Mode,
+    pub board: Board,
+    pub edges: Edges,
+    pub tick_ms: u64,
+    /// Which walls and obstacles were on the board, see `layout_name`.
+    /// Empty for scores saved before it was kept.
+    #[serde(default)]
+    pub layout: String,
+}
+
+impl HighScore {
+    /// The day it was set, as `YYYY-MM-DD` in UTC
+    pub fn day(&self) -> String {
+        let (year, month, day) = civil_from_days((self.date / 86_400) as i64);
+        format!("{year:04}-{month:02}-{day:02}")
+    }
+}
+
+/// A short name for the walls and obstacles under `rules`, so scores from
+/// different levels can be told apart: `OPEN` for an empty board, the name of
+/// a built-in level, or `CUSTOM` and a hash of the cells for anything else
+pub fn layout_name(rules: &Rules) -> String {
+    if rules.walls.is_empty() && rules.obstacles.is_empty() {
+        return "OPEN".to_string();
+    }
+    let walls = sorted(&rules.walls);
+    let obstacles = sorted(&rules.obstacles);
+    let builtin = BUILTIN_LEVELS.iter().find(|(_, text)| {
+        Level::from_text(text).is_ok_and(|level| {
+            level.board == rules.board && sorted(&level.walls) == walls && sorted(&level.obstacles) == obstacles
+        })
+    });
+    if let Some((name, _)) = builtin {
+        return name.to_uppercase();
+    }
+    // FNV-1a, folded to 16 bits, so the same layout gets the same name on every build
+    let mut hash: u32 = 0x811c_9dc5;
+    let coordinates = |cells: &[GridPos]| cells.iter().flat_map(|cell| [cell.x, cell.y]).collect::<Vec<_>>();
+    let values = [rules.board.width, rules.board.height]
+        .into_iter()
+        .chain(coordinates(&walls))
+        .chain([-1])
+        .chain(coordinates(&obstacles));
+    for value in values {
+        for byte in value.to_le_bytes() {
+            hash = (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193);
+        }
+    }
+    format!("CUSTOM {:04X}", (hash >> 16) ^ (hash & 0xffff))
+}
+
+fn sorted(cells: &[GridPos]) -> Vec<GridPos> {
+    let mut cells = cells.to_vec();
+    cells.sort_by_key(|cell| (cell.x, cell.y));
+    cells
+}
+
+/// Turns days since 1970-01-01 into a calendar date, after Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            (js_sys::Date::now() / 1000.0) as u64
+        } else {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ScoresFile<T> {
+    version: u32,
+    scores: Vec<T>,
+}
+
+/// The best `MAX_HIGH_SCORES` games, best first. Kept in the platform data
+/// directory natively and in `localStorage` in the browser.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HighScores {
+    pub scores: Vec<HighScore>,
+}
+
+impl HighScores {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&ScoresFile { version: SCORES_VERSION, scores: self.scores.iter().collect() })?)
+    }
+
+    /// Reads a saved table, skipping any entries that don't make sense
+    /// rather than losing the rest with them
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let file: ScoresFile<serde_json::Value> = serde_json::from_str(json)?;
+        if file.version != SCORES_VERSION {
+            anyhow::bail!("High scores have format version {}, this build reads version {}", file.version, SCORES_VERSION);
+        }
+        let mut table = Self::default();
+        for entry in file.scores {
+            match serde_json::from_value(entry) {
+                Ok(score) => {
+                    table.insert(score);
+                },
+                Err(e) => log::warn!("Skipping a broken high score: {e}"),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Whether `score` would make it onto the table
+    pub fn qualifies(&self, score: u32) -> bool {
+        score > 0 && (self.scores.len() < MAX_HIGH_SCORES || self.scores.last().is_some_and(|last| score > last.score))
+    }
+
+    /// Adds `score` in its place if it is good enough and returns where it
+    /// went. Ties go below the scores already there.
+    pub fn insert(&mut self, score: HighScore) -> Option<usize> {
+        if !self.qualifies(score.score) {
+            return None;
+        }
+        let rank = self.scores.iter().position(|other| other.score < score.score).unwrap_or(self.scores.len());
+        self.scores.insert(rank, score);
+        self.scores.truncate(MAX_HIGH_SCORES);
+        Some(rank)
+    }
+
+    /// The saved table, or an empty one if there is none or it can't be read
+    pub fn load() -> Self {
+        match storage::read() {
+            Ok(Some(json)) => Self::from_json(&json).unwrap_or_else(|e| {
+                log::warn!("Starting a new high score table, the saved one is unreadable: {e:#}");
+                storage::set_aside();
+                Self::default()
+            }),
+            Ok(None) => Self::default(),
+            Err(e) => {
+                log::warn!("{e:#}");
+                Self::default()
+            },
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        storage::write(&self.to_json()?)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod storage {
+    use std::path::PathBuf;
+
+    use anyhow::Context;
+
+    fn path() -> anyhow::Result<PathBuf> {
+        let dir = dirs::data_dir().context("Couldn't find a data directory for the high scores")?;
+        Ok(dir.join("snake").join("highscores.json"))
+    }
+
+    pub fn read() -> anyhow::Result<Option<String>> {
+        let path = path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(json) => Ok(Some(json)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Couldn't read high scores from {}", path.display())),
+        }
+    }
+
+    pub fn write(json: &str) -> anyhow::Result<()> {
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Couldn't create {}", dir.display()))?;
+        }
+        // Written beside it first so a crash partway leaves the old table whole
+        let partial = path.with_extension("json.tmp");
+        std::fs::write(&partial, json).with_context(|| format!("Couldn't write high scores to {}", partial.display()))?;
+        std::fs::rename(&partial, &path).with_context(|| format!("Couldn't write high scores to {}", path.display()))
+    }
+
+    /// Keeps an unreadable table around for a look, out of the way of the new one
+    pub fn set_aside() {
+        if let Ok(path) = path() {
+            let _ = std::fs::rename(&path, path.with_extension("json.bad"));
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod storage {
+    const KEY: &str = "snake-highscores";
+
+    fn local_storage() -> anyhow::Result<web_sys::Storage> {
+        web_sys::window()
+            .and_then(|window| window.local_storage().ok().flatten())
+            .ok_or_else(|| anyhow::anyhow!("localStorage isn't available, high scores won't be kept"))
+    }
+
+    pub fn read() -> anyhow::Result<Option<String>> {
+        local_storage()?.get_item(KEY).map_err(|e| anyhow::anyhow!("Couldn't read high scores: {e:?}"))
+    }
+
+    pub fn write(json: &str) -> anyhow::Result<()> {
+        local_storage()?.set_item(KEY, json).map_err(|e| anyhow::anyhow!("Couldn't save high scores: {e:?}"))
+    }
+
+    pub fn set_aside() {
+        if let Ok(storage) = local_storage() {
+            if let Ok(Some(json)) = storage.get_item(KEY) {
+                let _ = storage.set_item(&format!("{KEY}.bad"), &json);
+            }
+            let _ = storage.remove_item(KEY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, score: u32) -> HighScore {
+        HighScore {
+            name: name.to_string(),
+            score,
+            date: 0,
+            seed: 1,
+            mode: Mode::Solo,
+            board: Board::default(),
+            edges: Edges::Wrap,
+            tick_ms: 64,
+            layout: "OPEN".to_string(),
+        }
+    }
+
+    fn names(table: &HighScores) -> Vec<&str> {
+        table.scores.iter().map(|score| score.name.as_str()).collect()
+    }
+
+    #[test]
+    fn insert_ranks_best_first_with_ties_below() {
+        let mut table = HighScores::default();
+        assert!(!table.qualifies(0));
+        assert_eq!(table.insert(entry("a", 5)), Some(0));
+        assert_eq!(table.insert(entry("b", 9)), Some(0));
+        assert_eq!(table.insert(entry("c", 5)), Some(2));
+        assert_eq!(table.insert(entry("d", 7)), Some(1));
+        assert_eq!(names(&table), ["b", "d", "a", "c"]);
+    }
+
+    #[test]
+    fn insert_keeps_only_the_best() {
+        let mut table = HighScores::default();
+        for score in 1..=MAX_HIGH_SCORES as u32 {
+            table.insert(entry("full", score * 10));
+        }
+        assert!(!table.qualifies(10));
+        assert_eq!(table.insert(entry("tie", 10)), None);
+        assert!(table.qualifies(11));
+        assert_eq!(table.insert(entry("new", 55)), Some(5));
+        assert_eq!(table.scores.len(), MAX_HIGH_SCORES);
+        assert_eq!(table.scores.last().unwrap().score, 20);
+    }
+
+    #[test]
+    fn from_json_round_trips_and_skips_broken_entries() {
+        let mut table = HighScores::default();
+        table.insert(entry("a", 3));
+        table.insert(entry("b", 2));
+        assert_eq!(HighScores::from_json(&table.to_json().unwrap()).unwrap(), table);
+
+        let mut file: serde_json::Value = serde_json::from_str(&table.to_json().unwrap()).unwrap();
+        let scores = file["scores"].as_array_mut().unwrap();
+        scores[0]["score"] = "lots".into();
+        scores.push(serde_json::json!({ "name": "c" }));
+        // Saved before layouts were kept
+        scores[1].as_object_mut().unwrap().remove("layout");
+        let loaded = HighScores::from_json(&file.to_string()).unwrap();
+        assert_eq!(names(&loaded), ["b"]);
+        assert_eq!(loaded.scores[0].layout, "");
+    }
+
+    #[test]
+    fn from_json_rejects_other_versions() {
+        let json = format!(r#"{{ "version": {}, "scores": [] }}"#, SCORES_VERSION + 1);
+        assert!(HighScores::from_json(&json).is_err());
+        assert!(HighScores::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn days_become_calendar_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_722), (2023, 12, 31));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        let score = HighScore { date: 1_700_000_000, ..entry("a", 1) };
+        assert_eq!(score.day(), "2023-11-14");
+    }
+
+    #[test]
+    fn layouts_are_named_by_their_walls() {
+        let mut rules = Rules::default();
+        assert_eq!(layout_name(&rules), "OPEN");
+        let pillars = Level::find("pillars").unwrap().rules(1);
+        assert_eq!(layout_name(&pillars), "PILLARS");
+        // Order doesn't matter, any change does
+        let mut shuffled = pillars.clone();
+        shuffled.walls.reverse();
+        assert_eq!(layout_name(&shuffled), "PILLARS");
+        rules.walls = vec![GridPos::new(1, 1)];
+        let custom = layout_name(&rules);
+        assert!(custom.starts_with("CUSTOM "));
+        assert_eq!(layout_name(&rules), custom);
+        rules.obstacles = rules.walls.split_off(0);
+        assert_ne!(layout_name(&rules), custom);
+    }
+}
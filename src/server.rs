@@ -0,0 +1,447 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, RecvTimeoutError, Sender}},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    config::GameConfig,
+    net::{encode, Signal, Snapshot, Update},
+    rollback::Rollback,
+    snake::{InputQueue, SnakeState},
+    SnakeInputs,
+};
+
+/// Pause between everyone joining and the snakes moving
+const START_DELAY: Duration = Duration::from_secs(2);
+/// How long the end of a game stays up before the next starts
+const RESTART_DELAY: Duration = Duration::from_secs(3);
+/// How often a WebSocket thread checks for updates to send while waiting on its client
+const WEBSOCKET_POLL: Duration = Duration::from_millis(5);
+/// A client that takes longer than this to accept an update is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Numbers every client, whichever listener it came in on
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+
+/// What a client connection reports to the game loop. A client connects
+/// with where to queue its updates, as JSON lines, for a thread of its own
+/// to write so a slow one never holds up the game.
+enum Event {
+    Connected(usize, Sender<String>),
+    Signal(usize, Signal),
+    Disconnected(usize),
+}
+
+struct Client {
+    id: usize,
+    name: String,
+    outbox: Sender<String>,
+    /// The snake it steers, `None` for a spectator or before joining
+    player: Option<usize>,
+    joined: bool,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Phase {
+    /// Waiting for every snake to have a player
+    Lobby,
+    /// Everyone is in, the snakes move at the given time
+    Starting(Instant),
+    Playing,
+    /// The game ended, the next one is set up at the given time
+    Over(Instant),
+}
+
+/// Runs the game for everyone connected and streams it back to them. The
+/// server is the only one simulating, clients just send turns and draw the
+/// snapshots they get.
+///
+/// In lockstep, players simulate the game themselves and the server passes
+/// their turns around, following along to tell spectators and decide when
+/// the game is over.
+pub struct Server {
+    pub config: GameConfig,
+    pub game: SnakeState,
+    clients: Vec<Client>,
+    inputs: Vec<InputQueue>,
+    events: Receiver<Event>,
+    /// Kept to hand to listeners started later
+    sender: Sender<Event>,
+    phase: Phase,
+    /// The lockstep game being played
+    lockstep: Option<Rollback>,
+    address: SocketAddr,
+}
+
+impl Server {
+    /// Starts listening on `address`, games are played under `config`
+    pub fn bind(address: impl ToSocketAddrs, config: GameConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        let listener = TcpListener::bind(address).context("Couldn't start the server")?;
+        let address = listener.local_addr()?;
+        log::info!("Listening on {address}");
+        let (sender, events) = mpsc::channel();
+        let accepted = sender.clone();
+        std::thread::spawn(move || accept(listener, accepted));
+
+        let game = SnakeState::new(config.rules(), config.seed());
+        let inputs = game.snakes.iter().map(|_| InputQueue::default()).collect();
+        Ok(Self {
+            config,
+            game,
+            clients: Vec::new(),
+            inputs,
+            events,
+            sender,
+            phase: Phase::Lobby,
+            lockstep: None,
+            address,
+        })
+    }
+
+    /// Where TCP clients connect, with the port picked if bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Also takes players over WebSockets on `address`, for the browser build
+    pub fn serve_websockets(&mut self, address: impl ToSocketAddrs) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(address).context("Couldn't start the WebSocket server")?;
+        log::info!("Listening for WebSockets on {}", listener.local_addr()?);
+        let events = self.sender.clone();
+        std::thread::spawn(move || accept_websockets(listener, events));
+        Ok(())
+    }
+
+    /// Serves games forever
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let mut next_tick = Instant::now() + self.config.tick();
+        loop {
+            match self.events.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.tick();
+                    next_tick += self.config.tick();
+                },
+                Err(RecvTimeoutError::Disconnected) => anyhow::bail!("The server stopped accepting connections"),
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Connected(id, outbox) => {
+                self.clients.push(Client { id, name: String::new(), outbox, player: None, joined: false });
+            },
+            Event::Signal(id, Signal::Join { name }) => self.join(id, name),
+            Event::Signal(id, Signal::Input { direction }) => {
+                let player = self.clients.iter().find(|client| client.id == id).and_then(|client| client.player);
+                let moving = matches!(self.phase, Phase::Starting(_) | Phase::Playing) && self.config.lockstep.is_none();
+                if let (Some(player), true) = (player, moving) {
+                    self.inputs[player].push(direction, self.game.snakes[player].heading());
+                }
+            },
+            Event::Signal(id, Signal::Turns { tick, turns }) => {
+                let player = self.clients.iter().find(|client| client.id == id).and_then(|client| client.player);
+                if let Some(player) = player {
+                    self.receive_turns(player, tick, turns);
+                }
+            },
+            Event::Disconnected(id) => {
+                if let Some(i) = self.clients.iter().position(|client| client.id == id) {
+                    let client = self.clients.remove(i);
+                    log::info!("{} left", client.name);
+                    if self.phase == Phase::Lobby || matches!(self.phase, Phase::Starting(_)) {
+                        self.phase = Phase::Lobby;
+                        self.broadcast_waiting();
+                    } else if self.lockstep.is_some() && self.clients.iter().all(|client| client.player.is_none()) {
+                        // Nobody is left to send turns
+                        self.new_game();
+                    }
+                }
+            },
+        }
+    }
+
+    fn join(&mut self, id: usize, name: String) {
+        let taken = self.clients.iter().filter_map(|client| client.player).collect::<Vec<_>>();
+        let free = (0..self.game.snakes.len()).find(|player| !taken.contains(player));
+        let Some(client) = self.clients.iter_mut().find(|client| client.id == id) else {
+            return;
+        };
+        if client.joined {
+            send(client, &Update::Rejected { reason: "Already joined".to_string() });
+            return;
+        }
+        client.joined = true;
+        client.name = name;
+        // Snakes are only handed out between games
+        client.player = free.filter(|_| self.phase == Phase::Lobby);
+        match client.player {
+            Some(player) => log::info!("{} joined as player {}", client.name, player + 1),
+            None => log::info!("{} joined to watch", client.name),
+        }
+        let welcome = Update::Welcome {
+            player: client.player,
+            rules: self.game.rules.clone(),
+            tick_ms: self.config.tick_ms,
+        };
+        send(client, &welcome);
+        if self.phase == Phase::Lobby {
+            self.broadcast_waiting();
+        } else {
+            let snapshot = Update::Snapshot(Snapshot::of(&self.game));
+            if let Some(client) = self.clients.iter_mut().find(|client| client.id == id) {
+                send(client, &snapshot);
+            }
+        }
+    }
+
+    /// Tells everyone how many players are still missing, or starts the
+    /// game if there are none
+    fn broadcast_waiting(&mut self) {
+        let needed = self.game.snakes.len();
+        let joined = self.clients.iter().filter(|client| client.player.is_some()).count();
+        if joined < needed {
+            self.broadcast(&Update::Waiting { joined, needed });
+            return;
+        }
+        log::info!("Everyone is here, starting");
+        self.phase = Phase::Starting(Instant::now() + START_DELAY);
+        self.broadcast(&Update::Snapshot(Snapshot::of(&self.game)));
+    }
+
+    fn tick(&mut self) {
+        match self.phase {
+            Phase::Lobby => {},
+            Phase::Starting(at) => {
+                if Instant::now() >= at {
+                    self.phase = Phase::Playing;
+                    self.game.start();
+                    if let Some(netcode) = self.config.lockstep {
+                        self.lockstep = Some(Rollback::new(self.game.clone(), None, netcode));
+                        self.broadcast(&Update::Start { seed: self.game.seed, netcode });
+                    }
+                }
+            },
+            // Lockstep games move as turns come in
+            Phase::Playing if self.lockstep.is_some() => {},
+            Phase::Playing => {
+                let inputs = self.inputs.iter_mut().map(InputQueue::pop).collect::<Vec<_>>();
+                self.game.step(&inputs);
+                self.broadcast(&Update::Snapshot(Snapshot::of(&self.game)));
+                self.check_game_over();
+            },
+            Phase::Over(at) => {
+                if Instant::now() >= at {
+                    self.new_game();
+                }
+            },
+        }
+    }
+
+    fn check_game_over(&mut self) {
+        if self.game.ended {
+            let scores = self.game.snakes.iter().map(|snake| snake.score).collect();
+            self.broadcast(&Update::GameOver { winner: self.game.winner, scores });
+            self.phase = Phase::Over(Instant::now() + RESTART_DELAY);
+            self.lockstep = None;
+        }
+    }
+
+    /// Passes `player`'s lockstep turns on to everyone and follows the game
+    /// as far as all turns are in. Players who left are taken not to turn.
+    fn receive_turns(&mut self, player: usize, tick: u32, turns: Vec<Option<SnakeInputs>>) {
+        let Some(rollback) = &mut self.lockstep else {
+            return;
+        };
+        let before = rollback.confirmed_tick();
+        let end = tick + turns.len() as u32;
+        rollback.receive(player, tick, &turns);
+        let mut relayed = vec![Update::Turns { player, tick, turns }];
+        for other in 0..self.game.snakes.len() {
+            if self.clients.iter().any(|client| client.player == Some(other)) {
+                continue;
+            }
+            let from = rollback.next_unknown(other);
+            if from < end {
+                let turns = vec![None; (end - from) as usize];
+                rollback.receive(other, from, &turns);
+                relayed.push(Update::Turns { player: other, tick: from, turns });
+            }
+        }
+        let confirmed = (rollback.confirmed_tick() > before).then(|| rollback.confirmed().clone());
+        for update in &relayed {
+            self.broadcast(update);
+        }
+        if let Some(game) = confirmed {
+            self.game = game;
+            self.broadcast(&Update::Snapshot(Snapshot::of(&self.game)));
+            self.check_game_over();
+        }
+    }
+
+    /// Sets up the next game, keeping players on their snakes and handing
+    /// the snakes of anyone who left to spectators
+    fn new_game(&mut self) {
+        self.lockstep = None;
+        self.game.reset(self.config.seed());
+        self.inputs.iter_mut().for_each(InputQueue::clear);
+        self.phase = Phase::Lobby;
+        for player in 0..self.game.snakes.len() {
+            if self.clients.iter().any(|client| client.player == Some(player)) {
+                continue;
+            }
+            if let Some(client) = self.clients.iter_mut().find(|client| client.joined && client.player.is_none()) {
+                client.player = Some(player);
+                let welcome = Update::Welcome {
+                    player: client.player,
+                    rules: self.game.rules.clone(),
+                    tick_ms: self.config.tick_ms,
+                };
+                send(client, &welcome);
+            }
+        }
+        self.broadcast(&Update::Snapshot(Snapshot::of(&self.game)));
+        self.broadcast_waiting();
+    }
+
+    fn broadcast(&mut self, update: &Update) {
+        for client in self.clients.iter_mut().filter(|client| client.joined) {
+            send(client, update);
+        }
+    }
+}
+
+/// Queues `update` for `client`, a failed write shows up as a disconnect from
+/// its reader thread
+fn send(client: &mut Client, update: &Update) {
+    match encode(update) {
+        Ok(line) => {
+            let _ = client.outbox.send(line);
+        },
+        Err(e) => log::error!("Couldn't encode an update: {e}"),
+    }
+}
+
+/// Writes the lines queued for client `id` until it goes away or stops
+/// keeping up, then shuts the socket so its reader reports it gone
+fn write_lines(id: usize, mut stream: TcpStream, outgoing: Receiver<String>) {
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        log::warn!("Couldn't set up client {id}: {e}");
+    } else {
+        for line in outgoing {
+            if let Err(e) = stream.write_all(line.as_bytes()) {
+                log::warn!("Dropping client {id}, couldn't write to it: {e}");
+                break;
+            }
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+/// Hands every new connection to the game loop and reads its signals on a
+/// thread of its own
+fn accept(listener: TcpListener, events: Sender<Event>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Couldn't accept a connection: {e}");
+                continue;
+            },
+        };
+        let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+        let _ = stream.set_nodelay(true);
+        let reader = match stream.try_clone() {
+            Ok(reader) => BufReader::new(reader),
+            Err(e) => {
+                log::warn!("Couldn't accept a connection: {e}");
+                continue;
+            },
+        };
+        let (outbox, outgoing) = mpsc::channel();
+        if events.send(Event::Connected(id, outbox)).is_err() {
+            return;
+        }
+        std::thread::spawn(move || write_lines(id, stream, outgoing));
+        let events = events.clone();
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                match serde_json::from_str(&line) {
+                    Ok(signal) => {
+                        if events.send(Event::Signal(id, signal)).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e) => log::warn!("Ignoring a bad message from client {id}: {e}"),
+                }
+            }
+            let _ = events.send(Event::Disconnected(id));
+        });
+    }
+}
+
+/// Like `accept`, but for WebSocket clients. Each gets a thread that both
+/// reads its signals and writes the updates queued for it.
+fn accept_websockets(listener: TcpListener, events: Sender<Event>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Couldn't accept a WebSocket connection: {e}");
+                continue;
+            },
+        };
+        let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+        let events = events.clone();
+        std::thread::spawn(move || serve_websocket(id, stream, events));
+    }
+}
+
+fn serve_websocket(id: usize, stream: TcpStream, events: Sender<Event>) {
+    let _ = stream.set_nodelay(true);
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => return log::warn!("WebSocket handshake with client {id} failed: {e}"),
+    };
+    let timeouts = socket.get_ref().set_read_timeout(Some(WEBSOCKET_POLL));
+    if let Err(e) = timeouts.and_then(|()| socket.get_ref().set_write_timeout(Some(WRITE_TIMEOUT))) {
+        return log::warn!("Couldn't set up client {id}: {e}");
+    }
+    let (outbox, outgoing) = mpsc::channel();
+    if events.send(Event::Connected(id, outbox)).is_err() {
+        return;
+    }
+    'connection: loop {
+        // One update per text message
+        for line in outgoing.try_iter() {
+            if let Err(e) = socket.send(tungstenite::Message::text(line.trim_end())) {
+                log::warn!("Couldn't write to client {id}: {e}");
+                break 'connection;
+            }
+        }
+        match socket.read() {
+            Ok(tungstenite::Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(signal) => {
+                    if events.send(Event::Signal(id, signal)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => log::warn!("Ignoring a bad message from client {id}: {e}"),
+            },
+            Ok(tungstenite::Message::Close(_)) => break,
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            Err(_) => break,
+        }
+    }
+    let _ = events.send(Event::Disconnected(id));
+}
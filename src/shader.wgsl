@@ -20,6 +20,8 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    // u_min, v_min, u_max, v_max of the atlas tile
+    @location(9) atlas_rect: vec4<f32>,
 };
 
 
@@ -37,7 +39,7 @@ fn vs_main(
         instance.model_matrix_3,
     );
     var out: VertexOutput;
-    out.tex_coords = model.tex_coords;
+    out.tex_coords = mix(instance.atlas_rect.xy, instance.atlas_rect.zw, model.tex_coords);
     out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
     return out;
 }
@@ -53,4 +55,69 @@ var s_diffuse: sampler;
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     return textureSample(t_diffuse, s_diffuse, in.tex_coords);
-}
\ No newline at end of file
+}
+
+// 3D presentation: meshes lit by one directional light
+
+// Towards the light, from above and a little to the lower left of the board
+const LIGHT_DIRECTION: vec3<f32> = vec3<f32>(-0.36, -0.48, 0.8);
+// Light reaching faces turned away from it
+const AMBIENT: f32 = 0.4;
+
+struct MeshVertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+};
+
+struct MeshVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) normal: vec3<f32>,
+    // Mesh coordinates before the atlas rect is applied, the ground uses
+    // them to lay out its cells
+    @location(2) local_coords: vec2<f32>,
+};
+
+@vertex
+fn vs_mesh(
+    model: MeshVertexInput,
+    instance: InstanceInput
+) -> MeshVertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: MeshVertexOutput;
+    out.tex_coords = mix(instance.atlas_rect.xy, instance.atlas_rect.zw, model.tex_coords);
+    out.local_coords = model.tex_coords;
+    // Instances are only moved and turned, so the normals need no inverse transpose
+    out.normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
+    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+fn lighting(normal: vec3<f32>) -> f32 {
+    let diffuse = max(dot(normalize(normal), normalize(LIGHT_DIRECTION)), 0.0);
+    return AMBIENT + (1.0 - AMBIENT) * diffuse;
+}
+
+@fragment
+fn fs_mesh(in: MeshVertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    if color.a < 0.5 {
+        discard;
+    }
+    return vec4<f32>(color.rgb * lighting(in.normal), 1.0);
+}
+
+// A checkerboard with one square per board cell, counted in `local_coords`
+@fragment
+fn fs_ground(in: MeshVertexOutput) -> @location(0) vec4<f32> {
+    let cell = floor(in.local_coords);
+    let dark = (i32(cell.x) + i32(cell.y)) % 2 == 0;
+    let color = select(vec3<f32>(0.16, 0.2, 0.16), vec3<f32>(0.12, 0.15, 0.12), dark);
+    return vec4<f32>(color * lighting(in.normal), 1.0);
+}
@@ -1,14 +1,617 @@
-use std::ops::Range;
+use std::{collections::{HashSet, VecDeque}, ops::Range, time::Duration};
 
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use wgpu::util::DeviceExt;
 
+use crate::{texture, SnakeInputs};
 
+/// A cell on the board. `x` grows to the right and `y` grows upwards, with
+/// `(0, 0)` in the bottom left corner.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct GridPos {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl GridPos {
+    pub fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    /// The neighbouring cell in `direction`, which may lie off the board
+    pub fn step(self, direction: SnakeInputs) -> Self {
+        match direction {
+            SnakeInputs::Up => Self::new(self.x, self.y + 1),
+            SnakeInputs::Down => Self::new(self.x, self.y - 1),
+            SnakeInputs::Left => Self::new(self.x - 1, self.y),
+            SnakeInputs::Right => Self::new(self.x + 1, self.y),
+            SnakeInputs::Stay => self,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Board {
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Board {
+    pub fn new(width: i32, height: i32) -> Self {
+        Self { width, height }
+    }
+
+    pub fn center(&self) -> GridPos {
+        GridPos::new(self.width / 2, self.height / 2)
+    }
+
+    pub fn contains(&self, pos: GridPos) -> bool {
+        (0..self.width).contains(&pos.x) && (0..self.height).contains(&pos.y)
+    }
+
+    /// Wraps `pos` around the edges as if the board were a torus
+    pub fn wrap(&self, pos: GridPos) -> GridPos {
+        GridPos::new(pos.x.rem_euclid(self.width), pos.y.rem_euclid(self.height))
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::new(16, 16)
+    }
+}
+
+/// What happens to a snake that runs off the side of the board
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub enum Edges {
+    /// It comes back in on the opposite side
+    #[default]
+    Wrap,
+    /// It dies
+    Solid,
+}
+
+/// Where one snake starts
+#[derive(PartialEq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Spawn {
+    pub head: GridPos,
+    /// Which way the snake faces before its first move, its body trails behind
+    pub direction: SnakeInputs,
+}
+
+/// How a game is set up. Two games with the same rules, seed and inputs play
+/// out identically.
+#[derive(PartialEq, Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Rules {
+    pub board: Board,
+    /// Segments each snake starts with, laid out behind the head
+    pub start_length: usize,
+    /// Where the first apple goes, later ones are random
+    pub first_apple: Option<GridPos>,
+    #[serde(default)]
+    pub edges: Edges,
+    /// Cells that kill the snake, drawn as brick
+    #[serde(default)]
+    pub walls: Vec<GridPos>,
+    /// Cells that kill the snake, drawn as boulders
+    #[serde(default)]
+    pub obstacles: Vec<GridPos>,
+    /// Number of snakes, one per player
+    #[serde(default = "default_players")]
+    pub players: usize,
+    /// Where each player starts, in player order. Players past the end of
+    /// the list are lined up across the board.
+    #[serde(default)]
+    pub spawns: Vec<Spawn>,
+}
+
+fn default_players() -> usize {
+    1
+}
+
+impl Rules {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let Board { width, height } = self.board;
+        if !(2..=MAX_BOARD_SIZE).contains(&width) || !(2..=MAX_BOARD_SIZE).contains(&height) {
+            anyhow::bail!("Board must be between 2x2 and {MAX_BOARD_SIZE}x{MAX_BOARD_SIZE} cells, got {width}x{height}");
+        }
+        if !(1..=MAX_PLAYERS).contains(&self.players) {
+            anyhow::bail!("Players must be between 1 and {MAX_PLAYERS}, got {}", self.players);
+        }
+
+        let mut bodies: Vec<Vec<GridPos>> = Vec::new();
+        for player in 0..self.players {
+            let spawn = self.spawn(player);
+            let number = player + 1;
+            if spawn.direction == SnakeInputs::Stay {
+                anyhow::bail!("Player {number} must face Up, Down, Left or Right");
+            }
+            if !self.board.contains(spawn.head) {
+                anyhow::bail!("Player {number} spawns at ({}, {}), off the {width}x{height} board", spawn.head.x, spawn.head.y);
+            }
+            let (side, span) = match spawn.direction {
+                SnakeInputs::Up | SnakeInputs::Down => ("height", height),
+                _ => ("width", width),
+            };
+            if self.start_length == 0 || self.start_length > span as usize {
+                anyhow::bail!("Start length must be between 1 and the board {side} {span}, got {}", self.start_length);
+            }
+            if self.edges == Edges::Solid && !self.unwrapped_start_body(spawn).all(|pos| self.board.contains(pos)) {
+                anyhow::bail!("Player {number} doesn't fit between its spawn and the edge with a length of {}", self.start_length);
+            }
+            let body = self.start_body(player);
+            if let Some(other) = bodies.iter().position(|other| other.iter().any(|pos| body.contains(pos))) {
+                anyhow::bail!("Player {number} starts on top of player {}", other + 1);
+            }
+            bodies.push(body);
+        }
+
+        let under_snake = |pos: GridPos| bodies.iter().any(|body| body.contains(&pos));
+        for (kind, cells) in [("Wall", &self.walls), ("Obstacle", &self.obstacles)] {
+            for &cell in cells {
+                if !self.board.contains(cell) {
+                    anyhow::bail!("{kind} at ({}, {}) is off the {width}x{height} board", cell.x, cell.y);
+                }
+                if under_snake(cell) {
+                    anyhow::bail!("{kind} at ({}, {}) is under a snake", cell.x, cell.y);
+                }
+            }
+        }
+        if let Some(apple) = self.first_apple {
+            if !self.board.contains(apple) {
+                anyhow::bail!("First apple at ({}, {}) is off the {width}x{height} board", apple.x, apple.y);
+            }
+            if under_snake(apple) {
+                anyhow::bail!("First apple at ({}, {}) is under a snake", apple.x, apple.y);
+            }
+            if self.blocked().contains(&apple) {
+                anyhow::bail!("First apple at ({}, {}) is inside a wall", apple.x, apple.y);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `player` starts
+    pub fn spawn(&self, player: usize) -> Spawn {
+        if let Some(&spawn) = self.spawns.get(player) {
+            return spawn;
+        }
+        // One row each, spread evenly and facing alternate ways, so a lone
+        // snake sits in the middle facing left
+        let row = (player as i32 + 1) * self.board.height / (self.players as i32 + 1);
+        Spawn {
+            head: GridPos::new(self.board.center().x, row),
+            direction: if player.is_multiple_of(2) { SnakeInputs::Left } else { SnakeInputs::Right },
+        }
+    }
+
+    /// Where `player` starts, head first
+    pub fn start_body(&self, player: usize) -> Vec<GridPos> {
+        self.unwrapped_start_body(self.spawn(player)).map(|pos| self.board.wrap(pos)).collect()
+    }
+
+    fn unwrapped_start_body(&self, spawn: Spawn) -> impl Iterator<Item = GridPos> {
+        let behind = spawn.direction.opposite();
+        std::iter::successors(Some(spawn.head), move |pos| Some(pos.step(behind))).take(self.start_length)
+    }
+
+    /// Every wall and obstacle cell
+    pub fn blocked(&self) -> HashSet<GridPos> {
+        self.walls.iter().chain(&self.obstacles).copied().collect()
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            board: Board::default(),
+            start_length: 2,
+            first_apple: None,
+            edges: Edges::default(),
+            walls: Vec::new(),
+            obstacles: Vec::new(),
+            players: default_players(),
+            spawns: Vec::new(),
+        }
+    }
+}
+
+/// Largest board side, in cells
+pub const MAX_BOARD_SIZE: i32 = 256;
+
+/// Most snakes in one game, one per colour in the atlas
+pub const MAX_PLAYERS: usize = ATLAS_ROWS as usize;
+
+
+/// Most turns that can be waiting for a tick at once
+pub const INPUT_QUEUE_LEN: usize = 3;
+
+/// Turns pressed between ticks, handed to the game one per tick so quick
+/// successive presses are all kept.
+#[derive(Default, Debug)]
+pub struct InputQueue {
+    pending: VecDeque<SnakeInputs>,
+}
+
+impl InputQueue {
+    /// Queues `direction` unless the queue is full or it would not change the
+    /// heading. `applied` is the direction the snake moved in on the last
+    /// tick, turns are checked against it or the last queued turn so a quick
+    /// pair of presses can't reverse the snake into itself.
+    pub fn push(&mut self, direction: SnakeInputs, applied: SnakeInputs) -> bool {
+        let last = self.pending.back().copied().unwrap_or(applied);
+        if self.pending.len() >= INPUT_QUEUE_LEN || direction == last || direction == last.opposite() {
+            return false;
+        }
+        self.pending.push_back(direction);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<SnakeInputs> {
+        self.pending.pop_front()
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StepOutcome {
+    /// No snake has been given a direction yet, or the game is over
+    Idle,
+    Moved,
+    AteApple,
+    /// The game ended because snakes died
+    Died,
+    /// An apple was eaten and there is no room for another
+    Won,
+}
+
+/// One player's snake
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snake {
+    /// Segment positions, head first
+    pub body: Vec<GridPos>,
+    /// Direction each segment moved in to reach its cell, head first
+    pub directions: Vec<SnakeInputs>,
+    pub direction: Option<SnakeInputs>,
+    /// Dead snakes stay on the board where they died
+    pub alive: bool,
+    /// Apples eaten so far
+    pub score: u32,
+}
+
+impl Snake {
+    fn new(body: Vec<GridPos>, facing: SnakeInputs) -> Self {
+        Self {
+            directions: vec![facing; body.len()],
+            body,
+            direction: None,
+            alive: true,
+            score: 0,
+        }
+    }
+
+    pub fn head(&self) -> GridPos {
+        self.body[0]
+    }
+
+    /// The direction the snake is moving in, or facing before its first move
+    pub fn heading(&self) -> SnakeInputs {
+        self.direction.unwrap_or(self.directions[0])
+    }
+
+    pub fn length(&self) -> usize {
+        self.body.len()
+    }
+}
+
+/// The game rules, without any rendering attached.
+///
+/// `State` owns one of these and turns it into instances every frame, but it
+/// can just as well be driven on its own, one `step` per tick. Cloning it
+/// clones the apple generator too, so a clone stepped with the same inputs
+/// stays identical to the original.
+#[derive(Clone, Debug)]
 pub struct SnakeState {
+    pub rules: Rules,
+    /// Seed for apple placement, two games with the same seed and inputs play out identically
+    pub seed: u64,
+    rng: StdRng,
+    /// `rules.blocked()`, kept for collision checks
+    blocked: HashSet<GridPos>,
+    /// One snake per player, in player order
+    pub snakes: Vec<Snake>,
+    /// `None` once the snakes cover every free cell
+    pub apple: Option<GridPos>,
+    pub ended: bool,
+    /// The board filled up
+    pub won: bool,
+    /// With several players, the one left alive at the end or with the best
+    /// score on a full board. `None` for a draw.
+    pub winner: Option<usize>,
+    /// Ticks the snakes have spent moving
+    pub ticks: u32,
+}
+
+impl SnakeState {
+    pub fn new(rules: Rules, seed: u64) -> Self {
+        let snakes = (0..rules.players)
+            .map(|player| Snake::new(rules.start_body(player), rules.spawn(player).direction))
+            .collect();
+        let mut state = Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            blocked: rules.blocked(),
+            snakes,
+            rules,
+            apple: None,
+            ended: false,
+            won: false,
+            winner: None,
+            ticks: 0,
+        };
+        state.apple = state.rules.first_apple.or_else(|| state.spawn_apple());
+        state
+    }
+
+    /// Every cell not covered by a snake, a wall or an obstacle, row by row
+    pub fn free_cells(&self) -> Vec<GridPos> {
+        let snakes = self.snakes.iter().flat_map(|snake| snake.body.iter().copied()).collect::<HashSet<_>>();
+        (0..self.rules.board.height)
+            .flat_map(|y| (0..self.rules.board.width).map(move |x| GridPos::new(x, y)))
+            .filter(|pos| !self.blocked.contains(pos) && !snakes.contains(pos))
+            .collect()
+    }
+
+    /// Whether a wall or obstacle covers `pos`
+    pub fn is_blocked(&self, pos: GridPos) -> bool {
+        self.blocked.contains(&pos)
+    }
 
+    fn spawn_apple(&mut self) -> Option<GridPos> {
+        self.free_cells().choose(&mut self.rng).copied()
+    }
+
+    /// Puts the snakes back at their starting positions under the same rules
+    pub fn reset(&mut self, seed: u64) {
+        *self = Self::new(self.rules.clone(), seed);
+    }
+
+    /// Sets every snake moving the way it faces, without waiting for a turn
+    pub fn start(&mut self) {
+        if self.ended {
+            return;
+        }
+        for snake in &mut self.snakes {
+            if snake.alive && snake.direction.is_none() {
+                snake.direction = Some(snake.heading());
+            }
+        }
+    }
+
+    /// Time spent playing, given how long a tick lasts
+    pub fn elapsed(&self, tick: Duration) -> Duration {
+        tick * self.ticks
+    }
+
+    /// Advances the game by one tick, moving every snake at once.
+    ///
+    /// `inputs[i]` is the direction player `i` requested since the last tick,
+    /// if any. `Stay` and turning straight back into the neck are ignored. A snake dies
+    /// running into a wall, any snake's body, or another head arriving at the
+    /// same cell. With several players the game ends once one is left.
+    pub fn step(&mut self, inputs: &[Option<SnakeInputs>]) -> StepOutcome {
+        if self.ended {
+            return StepOutcome::Idle;
+        }
+        for (snake, &input) in self.snakes.iter_mut().zip(inputs) {
+            match input {
+                Some(d) if snake.alive && d != SnakeInputs::Stay && d != snake.heading().opposite() => {
+                    snake.direction = Some(d)
+                },
+                _ => {}
+            }
+        }
+        let board = self.rules.board;
+        // Where each moving snake's head goes, before wrapping
+        let targets = self.snakes
+            .iter()
+            .map(|snake| snake.direction.filter(|_| snake.alive).map(|d| (snake.head().step(d), d)))
+            .collect::<Vec<_>>();
+        if targets.iter().all(Option::is_none) {
+            return StepOutcome::Idle;
+        }
+        self.ticks += 1;
+
+        let eats = |target: Option<(GridPos, SnakeInputs)>| {
+            target.is_some_and(|(next, _)| Some(board.wrap(next)) == self.apple)
+        };
+        // Unless a snake grows or stands still, its tail moves out of the way before any head arrives
+        let occupied = self.snakes
+            .iter()
+            .zip(&targets)
+            .flat_map(|(snake, &target)| {
+                let keep_tail = target.is_none() || eats(target);
+                let kept = if keep_tail { snake.body.len() } else { snake.body.len() - 1 };
+                snake.body[..kept].iter().copied()
+            })
+            .collect::<HashSet<_>>();
+        let dies = targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| {
+                let Some((next, _)) = *target else {
+                    return false;
+                };
+                let head = board.wrap(next);
+                let off_edge = self.rules.edges == Edges::Solid && head != next;
+                let head_on = targets
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| j != i && other.is_some_and(|(other, _)| board.wrap(other) == head));
+                off_edge || head_on || occupied.contains(&head) || self.blocked.contains(&head)
+            })
+            .collect::<Vec<_>>();
+
+        let apple = self.apple;
+        let mut ate = false;
+        for ((snake, target), &dead) in self.snakes.iter_mut().zip(targets).zip(&dies) {
+            let Some((next, d)) = target else {
+                continue;
+            };
+            if dead {
+                snake.alive = false;
+                snake.direction = None;
+                continue;
+            }
+            let head = board.wrap(next);
+            if Some(head) == apple {
+                snake.score += 1;
+                ate = true;
+            } else {
+                snake.body.pop();
+                snake.directions.pop();
+            }
+            snake.body.insert(0, head);
+            snake.directions.insert(0, d);
+        }
+        if ate {
+            self.apple = self.spawn_apple();
+        }
+
+        let alive = self.snakes.iter().enumerate().filter(|(_, snake)| snake.alive).map(|(i, _)| i).collect::<Vec<_>>();
+        let game_over = if self.snakes.len() == 1 { alive.is_empty() } else { alive.len() <= 1 };
+        if game_over {
+            self.winner = alive.first().copied().filter(|_| self.snakes.len() > 1);
+            self.finish();
+            return StepOutcome::Died;
+        }
+        if !ate {
+            return StepOutcome::Moved;
+        }
+        if self.apple.is_none() {
+            self.won = true;
+            self.winner = self.best_score(&alive).filter(|_| self.snakes.len() > 1);
+            self.finish();
+            return StepOutcome::Won;
+        }
+        StepOutcome::AteApple
+    }
+
+    fn finish(&mut self) {
+        self.ended = true;
+        for snake in &mut self.snakes {
+            snake.direction = None;
+        }
+    }
+
+    /// The one player out of `players` with the highest score, `None` on a tie
+    fn best_score(&self, players: &[usize]) -> Option<usize> {
+        let best = players.iter().map(|&i| self.snakes[i].score).max()?;
+        let mut leaders = players.iter().filter(|&&i| self.snakes[i].score == best);
+        match (leaders.next(), leaders.next()) {
+            (Some(&leader), None) => Some(leader),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SnakeState {
+    fn default() -> Self {
+        Self::new(Rules::default(), random_seed())
+    }
+}
+
+/// A seed that differs between runs, for games that don't ask for a specific one
+pub fn random_seed() -> u64 {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            (js_sys::Math::random() * u64::MAX as f64) as u64
+        } else {
+            use std::time::{SystemTime, UNIX_EPOCH};
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or_default()
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct Instance {
+    /// What is drawn, which picks the mesh in 3D
+    pub sprite: Sprite,
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    /// Region of the texture atlas drawn on the quad, as `[u_min, v_min, u_max, v_max]`
+    pub atlas_rect: [f32; 4],
+}
+
+/// Size of `snake_atlas.png` in tiles
+pub const ATLAS_COLUMNS: u32 = 8;
+pub const ATLAS_ROWS: u32 = 4;
+/// Side of one atlas tile in pixels
+pub const ATLAS_TILE_SIZE: u32 = 16;
+
+/// Tiles of `snake_atlas.png`.
+///
+/// Every row holds the head, body, corner and tail of a snake in one colour,
+/// drawn as if the snake were moving right. The corner joins the left and top
+/// edges of its tile. The apple, wall and obstacle sit in the fifth column.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sprite {
+    Head,
+    Body,
+    Corner,
+    Tail,
+    Apple,
+    Wall,
+    Obstacle,
+}
+
+impl Sprite {
+    pub const ALL: [Sprite; 7] = [
+        Sprite::Head,
+        Sprite::Body,
+        Sprite::Corner,
+        Sprite::Tail,
+        Sprite::Apple,
+        Sprite::Wall,
+        Sprite::Obstacle,
+    ];
+
+    /// Where this sprite is in the atlas, `colour` picks the row for snake parts
+    pub fn atlas_rect(self, colour: u32) -> [f32; 4] {
+        let (column, row) = match self {
+            Sprite::Head => (0, colour),
+            Sprite::Body => (1, colour),
+            Sprite::Corner => (2, colour),
+            Sprite::Tail => (3, colour),
+            Sprite::Apple => (4, 0),
+            Sprite::Wall => (4, 1),
+            Sprite::Obstacle => (4, 2),
+        };
+        let width = (ATLAS_COLUMNS * ATLAS_TILE_SIZE) as f32;
+        let height = (ATLAS_ROWS * ATLAS_TILE_SIZE) as f32;
+        let x = (column * ATLAS_TILE_SIZE) as f32;
+        let y = (row % ATLAS_ROWS * ATLAS_TILE_SIZE) as f32;
+        let tile = ATLAS_TILE_SIZE as f32;
+        // Stay half a texel inside the tile so its neighbours never bleed in
+        [
+            (x + 0.5) / width,
+            (y + 0.5) / height,
+            (x + tile - 0.5) / width,
+            (y + tile - 0.5) / height,
+        ]
+    }
 }
 
 
@@ -19,12 +622,14 @@ pub struct Instance {
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
+    atlas_rect: [f32; 4],
 }
 
 impl Instance {
     pub fn to_raw(&self) -> InstanceRaw {
         InstanceRaw { 
             model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),  
+            atlas_rect: self.atlas_rect,
         }
     }
 
@@ -64,6 +669,11 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
@@ -78,7 +688,8 @@ pub trait Vertex {
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
     pub position: [f32; 3],
-    pub tex_coords: [f32; 2]
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
 }
 
 impl Vertex for ModelVertex {
@@ -113,7 +724,59 @@ pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
-    pub elements: u32
+    pub elements: u32,
+    /// Index into its model's materials, unused for meshes drawn on their own
+    pub material: usize,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, name: &str, vertices: &[ModelVertex], indices: &[u32]) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{name} Vertex Buffer")),
+            contents: bytemuck::cast_slice(vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{name} Index Buffer")),
+            contents: bytemuck::cast_slice(indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+        Self { name: name.to_string(), vertex_buffer, index_buffer, elements: indices.len() as u32, material: 0 }
+    }
+}
+
+/// A texture to draw meshes with, bound in place of the sprite atlas
+pub struct Material {
+    pub name: String,
+    pub diffuse_texture: texture::Texture,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Material {
+    /// `layout` is the one the sprite atlas is bound with
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, name: &str, diffuse_texture: texture::Texture) -> Self {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+            ],
+            label: Some(name),
+        });
+        Self { name: name.to_string(), diffuse_texture, bind_group }
+    }
+}
+
+/// Meshes loaded from a file, see `model::load`
+pub struct Model {
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
 }
 
 
@@ -128,6 +791,12 @@ pub trait DrawModel<'a> {
         mesh: &'a Mesh,
         instances: Range<u32>,
     );
+    /// Binds each mesh's material to group 0 before drawing it
+    fn draw_model_instanced(
+        &mut self,
+        model: &'a Model,
+        instances: Range<u32>,
+    );
 }
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where
@@ -146,5 +815,142 @@ where
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.draw_indexed(0..mesh.elements, 0, instances);
     }
+
+    fn draw_model_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+    ){
+        for mesh in &model.meshes {
+            self.set_bind_group(0, &model.materials[mesh.material].bind_group, &[]);
+            self.draw_mesh_instanced(mesh, instances.clone());
+        }
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lone snake of `start_length` with its head at `head`, facing left,
+    /// and the first apple out of the way in a corner
+    fn game(head: GridPos, start_length: usize, edges: Edges, walls: Vec<GridPos>) -> SnakeState {
+        let rules = Rules {
+            board: Board::new(10, 10),
+            start_length,
+            first_apple: Some(GridPos::new(0, 9)),
+            edges,
+            walls,
+            spawns: vec![Spawn { head, direction: SnakeInputs::Left }],
+            ..Rules::default()
+        };
+        SnakeState::new(rules, 1)
+    }
+
+    #[test]
+    fn step_moves_the_snake_once_started() {
+        let mut game = game(GridPos::new(5, 5), 3, Edges::Solid, Vec::new());
+        assert_eq!(game.step(&[None]), StepOutcome::Idle);
+        game.start();
+        assert_eq!(game.step(&[None]), StepOutcome::Moved);
+        assert_eq!(game.snakes[0].body, [GridPos::new(4, 5), GridPos::new(5, 5), GridPos::new(6, 5)]);
+        // Turning back into the neck is ignored
+        assert_eq!(game.step(&[Some(SnakeInputs::Right)]), StepOutcome::Moved);
+        assert_eq!(game.snakes[0].head(), GridPos::new(3, 5));
+        assert_eq!(game.step(&[Some(SnakeInputs::Up)]), StepOutcome::Moved);
+        assert_eq!(game.snakes[0].head(), GridPos::new(3, 6));
+        assert_eq!(game.ticks, 3);
+    }
+
+    #[test]
+    fn step_eats_and_grows() {
+        let mut game = game(GridPos::new(5, 5), 2, Edges::Solid, Vec::new());
+        game.apple = Some(GridPos::new(4, 5));
+        game.start();
+        assert_eq!(game.step(&[None]), StepOutcome::AteApple);
+        let snake = &game.snakes[0];
+        assert_eq!(snake.score, 1);
+        assert_eq!(snake.body, [GridPos::new(4, 5), GridPos::new(5, 5), GridPos::new(6, 5)]);
+        let apple = game.apple.expect("there is room for another apple");
+        assert!(!snake.body.contains(&apple));
+    }
+
+    #[test]
+    fn step_dies_on_own_body() {
+        let mut game = game(GridPos::new(5, 5), 5, Edges::Wrap, Vec::new());
+        game.start();
+        for turn in [SnakeInputs::Up, SnakeInputs::Right] {
+            assert_eq!(game.step(&[Some(turn)]), StepOutcome::Moved);
+        }
+        assert_eq!(game.step(&[Some(SnakeInputs::Down)]), StepOutcome::Died);
+        assert!(!game.snakes[0].alive);
+        assert!(game.ended);
+        assert_eq!(game.step(&[None]), StepOutcome::Idle);
+    }
+
+    #[test]
+    fn step_follows_the_tail_out_of_its_cell() {
+        let mut game = game(GridPos::new(5, 5), 4, Edges::Wrap, Vec::new());
+        game.start();
+        for turn in [SnakeInputs::Up, SnakeInputs::Right, SnakeInputs::Down] {
+            assert_eq!(game.step(&[Some(turn)]), StepOutcome::Moved);
+        }
+        // Where the tail was a tick ago
+        assert_eq!(game.snakes[0].head(), GridPos::new(6, 5));
+    }
+
+    #[test]
+    fn step_dies_on_a_wall() {
+        let mut game = game(GridPos::new(5, 5), 2, Edges::Wrap, vec![GridPos::new(4, 5)]);
+        game.start();
+        assert_eq!(game.step(&[None]), StepOutcome::Died);
+        assert_eq!(game.snakes[0].head(), GridPos::new(5, 5));
+    }
+
+    #[test]
+    fn step_dies_off_a_solid_edge() {
+        let mut game = game(GridPos::new(0, 5), 2, Edges::Solid, Vec::new());
+        game.start();
+        assert_eq!(game.step(&[None]), StepOutcome::Died);
+    }
+
+    #[test]
+    fn step_wraps_around_the_edge() {
+        let mut game = game(GridPos::new(0, 5), 2, Edges::Wrap, Vec::new());
+        game.start();
+        assert_eq!(game.step(&[None]), StepOutcome::Moved);
+        assert_eq!(game.snakes[0].body, [GridPos::new(9, 5), GridPos::new(0, 5)]);
+        assert_eq!(game.step(&[Some(SnakeInputs::Down)]), StepOutcome::Moved);
+        assert_eq!(game.step(&[None]), StepOutcome::Moved);
+        assert_eq!(game.step(&[None]), StepOutcome::Moved);
+        assert_eq!(game.snakes[0].head(), GridPos::new(9, 2));
+    }
+
+    #[test]
+    fn input_queue_rejects_repeats_and_reversals() {
+        let mut queue = InputQueue::default();
+        assert!(!queue.push(SnakeInputs::Left, SnakeInputs::Left));
+        assert!(!queue.push(SnakeInputs::Right, SnakeInputs::Left));
+        assert!(queue.push(SnakeInputs::Up, SnakeInputs::Left));
+        // Checked against the queued turn now, not the applied one
+        assert!(!queue.push(SnakeInputs::Down, SnakeInputs::Left));
+        assert!(queue.push(SnakeInputs::Right, SnakeInputs::Left));
+        assert_eq!(queue.pop(), Some(SnakeInputs::Up));
+        assert_eq!(queue.pop(), Some(SnakeInputs::Right));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn input_queue_holds_at_most_its_capacity() {
+        let mut queue = InputQueue::default();
+        let turns = [SnakeInputs::Up, SnakeInputs::Left, SnakeInputs::Down, SnakeInputs::Right];
+        let taken = turns.iter().filter(|&&turn| queue.push(turn, SnakeInputs::Right)).count();
+        assert_eq!(taken, INPUT_QUEUE_LEN);
+        assert_eq!(queue.len(), INPUT_QUEUE_LEN);
+        assert_eq!(queue.pop(), Some(SnakeInputs::Up));
+        assert!(queue.push(SnakeInputs::Right, SnakeInputs::Up));
+        queue.clear();
+        assert!(queue.is_empty());
+    }
+}
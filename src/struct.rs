@@ -1,26 +1,86 @@
-use std::time::{Duration, Instant};
+use std::path::PathBuf;
+
+use anyhow::Context;
 
-use rand::{distributions::Uniform, prelude::Distribution};
 use wgpu::{util::DeviceExt, Color};
 // lib.rs
-use winit::{event::{ElementState, KeyEvent, WindowEvent}, keyboard::{Key, KeyCode, NamedKey, PhysicalKey, SmolStr}, window::Window};
-use crate::{camera::{Camera, CameraStaging, CameraUniform}, snake::{Instance, InstanceRaw}, SnakeInputs};
-use crate::texture;
+use winit::{event::{ElementState, KeyEvent, MouseButton, WindowEvent}, keyboard::{KeyCode, PhysicalKey}, window::Window};
+use crate::{camera::{Bounds, Camera, CameraPreset, CameraStaging, CameraUniform}, config::GameConfig, controller::{Controller, ControllerKind, GameView}, snake::{Board, Edges, GridPos, Instance, InstanceRaw, Snake, SnakeState, Sprite, StepOutcome, MAX_PLAYERS}, SnakeInputs};
+use crate::{board3d::Board3d, replay::{Replay, ReplayPlayer}, text::TextRenderer, texture};
+use crate::clock::{FixedTimestep, Instant};
+use crate::net::{Connection, Remote, Signal, Update};
+use crate::rollback::Rollback;
+use crate::scores::{HighScore, HighScores, Mode};
 use cgmath::prelude::*;
 
-const SPEED: f32 = 0.1;
+const GAME_OVER_COLOR: Color = Color { r: 0.25, g: 0.0, b: 0.0, a: 1.0 };
+
+/// Ticks run back to back after a slow frame, any more are dropped
+const MAX_CATCH_UP_TICKS: u32 = 4;
+
+const HUD_SCALE: f32 = 3.0;
+const HUD_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+/// Laid over the board while paused
+const PAUSE_DIM: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+/// Scores set in the game just played, on the high score table
+const NEW_SCORE_COLOR: [f32; 4] = [1.0, 0.85, 0.2, 1.0];
+/// The high score table is smaller than the rest of the HUD to fit the window
+const TABLE_SCALE: f32 = 2.0;
+/// Each player's colour in the atlas, for their score
+const PLAYER_COLORS: [[f32; 4]; MAX_PLAYERS] = [
+    [0.35, 0.69, 0.28, 1.0],
+    [0.27, 0.51, 0.86, 1.0],
+    [0.92, 0.59, 0.2, 1.0],
+    [0.67, 0.35, 0.78, 1.0],
+];
+
+/// Up, down, left and right for each player
+const PLAYER_KEYS: [[KeyCode; 4]; MAX_PLAYERS] = [
+    [KeyCode::KeyW, KeyCode::KeyS, KeyCode::KeyA, KeyCode::KeyD],
+    [KeyCode::ArrowUp, KeyCode::ArrowDown, KeyCode::ArrowLeft, KeyCode::ArrowRight],
+    [KeyCode::KeyI, KeyCode::KeyK, KeyCode::KeyJ, KeyCode::KeyL],
+    [KeyCode::Numpad8, KeyCode::Numpad5, KeyCode::Numpad4, KeyCode::Numpad6],
+];
+
 
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Phase {
+    /// The board is laid out but nothing moves until a direction or Space is pressed
+    Ready,
+    Playing,
+    /// Time is stopped mid-game by P, or because the window lost focus or the
+    /// page was hidden
+    Paused,
+    /// A snake died or the board filled up, R starts a new game
+    GameOver,
+    /// The high score table, opened with H from the game over screen
+    HighScores,
+}
+
+/// Where frames end up
+pub enum RenderTarget<'a> {
+    /// Presented to a window
+    Window {
+        surface: wgpu::Surface<'a>,
+        // The window must be declared after the surface so
+        // it gets dropped after it as the surface contains
+        // unsafe references to the window's resources.
+        window: &'a Window,
+    },
+    /// Kept in a texture that can be read back with `State::render_to_image`
+    Offscreen {
+        texture: wgpu::Texture,
+    },
+}
 
 pub struct State<'a> {
-    pub surface: wgpu::Surface<'a>,
+    pub target: RenderTarget<'a>,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    /// Format and size of the frames, also used for offscreen targets
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
-    // The window must be declared after the surface so
-    // it gets dropped after it as the surface contains
-    // unsafe references to the window's resources.
-    pub window: &'a Window,
+    pub game_config: GameConfig,
     pub clear_color: Color,
     pub render_pipeline: wgpu::RenderPipeline,
     pub vertex_buffer: wgpu::Buffer,
@@ -33,34 +93,51 @@ pub struct State<'a> {
     pub diffuse_bind_group: wgpu::BindGroup,
     pub diffuse_texture: texture::Texture,
     pub camera_staging: CameraStaging,
+    pub game: SnakeState,
+    pub phase: Phase,
     pub instances: Vec<Instance>,
     pub instance_buffer: wgpu::Buffer,
-    pub direction: Option<SnakeInputs>,
-    pub last_direction: u32,
-    pub first_direction: u32,
-    pub directions: Vec<SnakeInputs>,
-    pub last_updated: Instant,
-    pub apple_vertex_buffer: wgpu::Buffer,
+    /// The snakes before the last tick, drawn sliding from there to where
+    /// they are now
+    pub previous_snakes: Vec<Snake>,
+    /// Who steers each snake, in player order
+    pub controllers: Vec<Box<dyn Controller>>,
+    /// Set when following a game on a server instead of playing locally
+    pub remote: Option<Remote>,
+    /// Turns the time between frames into game ticks
+    pub timestep: FixedTimestep,
     pub apple_instances_buffer: wgpu::Buffer,
     pub apple_instances: Vec<Instance>,
-    pub ended: bool,
+    pub wall_instances_buffer: wgpu::Buffer,
+    pub wall_instances: Vec<Instance>,
+    /// How many cells past the edges of a wrapping board the apples and
+    /// walls are drawn again, for a camera looking across the seam
+    pub ghost_cells: i32,
+    pub text: TextRenderer,
+    /// Draws the board when `game_config.three_d` is set
+    pub board3d: Board3d,
+    /// The game so far, restarted along with it
+    pub recording: Replay,
+    /// Where to save `recording` when a game ends
+    pub record_to: Option<PathBuf>,
+    /// Set while a replay is driving the game instead of the keyboard
+    pub playback: Option<ReplayPlayer>,
+    pub high_scores: HighScores,
+    /// Where the scores from the last game landed on `high_scores`
+    pub new_high_scores: Vec<usize>,
 }
 
-const VERTICES: &[Vertex] = &[
-    // Changed
-    Vertex { position: [-0.05, 0.05, 0.0], tex_coords: [0.0, 0.0], }, // A
-    Vertex { position: [-0.05, -0.05, 0.0], tex_coords: [0.0, 0.0], }, // B
-    Vertex { position: [0.05, -0.05, 0.0], tex_coords: [0.0, 0.0], }, // C
-    Vertex { position: [0.05, 0.05, 0.0], tex_coords: [0.0, 0.0], }, // D
-];
-
-const APPLE_VERTICES: &[Vertex] = &[
-    // Changed
-    Vertex { position: [-0.05, 0.05, 0.0], tex_coords: [1.0, 1.0], }, // A
-    Vertex { position: [-0.05, -0.05, 0.0], tex_coords: [1.0, 1.0], }, // B
-    Vertex { position: [0.05, -0.05, 0.0], tex_coords: [1.0, 1.0], }, // C
-    Vertex { position: [0.05, 0.05, 0.0], tex_coords: [1.0, 1.0], }, // D
-];
+/// A quad covering one board cell of `cell_size`. The texture coordinates run
+/// over the whole tile, each instance picks its own tile of the atlas.
+fn quad_vertices(cell_size: f32) -> [Vertex; 4] {
+    let h = cell_size / 2.0;
+    [
+        Vertex { position: [-h, h, 0.0], tex_coords: [0.0, 0.0], }, // A
+        Vertex { position: [-h, -h, 0.0], tex_coords: [0.0, 1.0], }, // B
+        Vertex { position: [h, -h, 0.0], tex_coords: [1.0, 1.0], }, // C
+        Vertex { position: [h, h, 0.0], tex_coords: [1.0, 0.0], }, // D
+    ]
+}
 
 
 const INDICES: &[u16] = &[
@@ -82,35 +159,9 @@ struct Vertex {
 impl<'a> State<'a> {
     
     // Creating some of the wgpu types requires async code
-    pub async fn new(window: &'a Window) -> Self {
+    pub async fn new(window: &'a Window, game_config: GameConfig) -> Self {
         let size = window.inner_size();
-        let instances = vec![
-            Instance {
-                position: cgmath::Vector3 { x: 0.0, y: 0.0, z: 0.0 },
-                rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-            },
-            Instance {
-                position: cgmath::Vector3 { x: 0.1, y: 0.0, z: 0.0 },
-                rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-            }
 
-        ];
-        
-        //let step = Uniform::new(-9, 10);
-        //let mut rng = rand::thread_rng();
-        //let choice = step.sample(&mut rng);
-        //let x = choice as f32 * SPEED;
-        //let choice = step.sample(&mut rng);
-        //let y = choice as f32 * SPEED;
-            let x = 0.4;
-            let y = 0.4;
-        let apple_instances = vec![
-            Instance {
-                position: cgmath::Vector3 { x, y, z: 0.0 },
-                rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-            }
-        ];
-        
         // The instance is a handle to our GPU
         // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -132,22 +183,7 @@ impl<'a> State<'a> {
             },
         ).await.unwrap();
 
-
-        let (device, queue) = adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                required_features: wgpu::Features::empty(),
-                // WebGL doesn't support all of wgpu's features, so if
-                // we're building for the web, we'll have to disable some.
-                required_limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
-                } else {
-                    wgpu::Limits::default()
-                },
-                label: None,
-                memory_hints: Default::default(),
-            },
-            None, // Trace path
-        ).await.unwrap();
+        let (device, queue) = request_device(&adapter).await.unwrap();
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
@@ -168,6 +204,71 @@ impl<'a> State<'a> {
         };
         surface.configure(&device, &config);
 
+        Self::with_target(device, queue, config, game_config, RenderTarget::Window { surface, window })
+    }
+
+    /// Creates a `State` without a window that draws into an offscreen texture
+    /// the size of the configured window. Falls back to a software adapter when
+    /// there is no GPU, so it also works on CI machines.
+    pub async fn new_headless(game_config: GameConfig) -> anyhow::Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+
+        let mut adapter = None;
+        for force_fallback_adapter in [false, true] {
+            adapter = instance.request_adapter(
+                &wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::default(),
+                    compatible_surface: None,
+                    force_fallback_adapter,
+                },
+            ).await;
+            if adapter.is_some() {
+                break;
+            }
+        }
+        let adapter = adapter.context("No GPU or software adapter available")?;
+        let (device, queue) = request_device(&adapter).await.context("Couldn't open the graphics device")?;
+
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: game_config.window_width,
+            height: game_config.window_height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+            desired_maximum_frame_latency: 2,
+        };
+        let texture = create_offscreen_texture(&device, &config);
+
+        Ok(Self::with_target(device, queue, config, game_config, RenderTarget::Offscreen { texture }))
+    }
+
+    /// Builds the pipeline and game around an already configured target
+    fn with_target(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        config: wgpu::SurfaceConfiguration,
+        game_config: GameConfig,
+        target: RenderTarget<'a>,
+    ) -> Self {
+        let size = winit::dpi::PhysicalSize::new(config.width, config.height);
+        let game = SnakeState::new(game_config.rules(), game_config.seed());
+        let instances = snake_instances(&game, &game.snakes, 1.0, game_config.cell_size);
+        let timestep = FixedTimestep::new(game_config.tick(), MAX_CATCH_UP_TICKS);
+        let apple_instances = apple_instances(&game, game_config.cell_size, 0);
+        // Headless runs start from an empty table so screenshots don't depend on the player's scores
+        let high_scores = match target {
+            RenderTarget::Window { .. } => HighScores::load(),
+            RenderTarget::Offscreen { .. } => HighScores::default(),
+        };
+        let wall_instances = wall_instances(&game, game_config.cell_size, 0);
+        let recording = Replay::new(game.seed, game.rules.clone());
+        let controllers = create_controllers(&game_config, game.snakes.len());
+
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor { 
             label: Some("Shader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()) 
@@ -192,6 +293,7 @@ impl<'a> State<'a> {
                 usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             }
         );
+        let wall_instances_buffer = create_wall_buffer(&device, &wall_instances);
 
 
         let camera = Camera {
@@ -206,8 +308,6 @@ impl<'a> State<'a> {
             fovy: 45.0,
             znear: 0.1,
             zfar: 100.0,
-            pitch: 0.0,
-            yaw: 0.0
         };
 
         let mut camera_uniform = CameraUniform::new();
@@ -267,7 +367,7 @@ impl<'a> State<'a> {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
+            mag_filter: wgpu::FilterMode::Nearest,
             min_filter: wgpu::FilterMode::Nearest,
             mipmap_filter: wgpu::FilterMode::Nearest,
             ..Default::default()
@@ -342,7 +442,7 @@ impl<'a> State<'a> {
                 entry_point: "fs_main",
                 targets: &[Some(wgpu::ColorTargetState { // 4.
                     format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
                 compilation_options: wgpu::PipelineCompilationOptions::default(),
@@ -370,15 +470,7 @@ impl<'a> State<'a> {
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(VERTICES),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
-        );
-
-        let apple_vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Apple vertex Buffer"),
-                contents: bytemuck::cast_slice(APPLE_VERTICES),
+                contents: bytemuck::cast_slice(&quad_vertices(game_config.cell_size)),
                 usage: wgpu::BufferUsages::VERTEX,
             }
         );
@@ -417,17 +509,24 @@ impl<'a> State<'a> {
         );
 
         
-        let camera_staging = CameraStaging::new(camera);
+        let preset = match CameraPreset::for_board(&game.rules.board, size.width.min(size.height)) {
+            // 3D is hard to make out looking straight down
+            CameraPreset::TopDown if game_config.three_d => CameraPreset::ThreeQuarter,
+            preset => preset,
+        };
+        let mut board3d = Board3d::new(&device, &config, &render_pipeline_layout, &shader, &diffuse_rgba, game_config.cell_size);
+        board3d.load_models(&device, &queue, &texture_bind_group_layout, &game_config.models, game_config.cell_size);
+        let camera_staging = CameraStaging::new(camera, preset, camera_bounds(&game, game_config.cell_size));
 
-        let last_direction = (instances.len()-1) as u32;
+        let text = TextRenderer::new(&device, &queue, config.format, config.width, config.height);
 
         Self {
-            window,
-            surface,
+            target,
             device,
             queue,
             config,
             size,
+            game_config,
             clear_color: Color::BLACK,
             render_pipeline,
             vertex_buffer,
@@ -439,35 +538,53 @@ impl<'a> State<'a> {
             diffuse_bind_group,
             diffuse_texture,
             camera_staging,
+            previous_snakes: game.snakes.clone(),
+            game,
+            phase: Phase::Ready,
             instances,
             instance_buffer,
-            direction: None,
-            last_direction,
-            first_direction: 0,
-            directions: vec![SnakeInputs::Left],
-            last_updated: Instant::now(),
-            apple_vertex_buffer,
+            controllers,
+            remote: None,
+            timestep,
             apple_instances,
             apple_instances_buffer,
-            ended: false
+            wall_instances,
+            wall_instances_buffer,
+            ghost_cells: 0,
+            text,
+            board3d,
+            recording,
+            record_to: None,
+            playback: None,
+            high_scores,
+            new_high_scores: Vec::new(),
         }
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    /// The window being drawn to, `None` when rendering offscreen
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            RenderTarget::Window { window, .. } => Some(window),
+            RenderTarget::Offscreen { .. } => None,
+        }
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
-            let new_size = winit::dpi::PhysicalSize::new(new_size.width, new_size.width);
-
-        // Update the size
             self.size = new_size;
 
-        // Update the configuration for the surface to match the new size
+            // Update the configuration for the surface to match the new size
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            match &mut self.target {
+                RenderTarget::Window { surface, .. } => surface.configure(&self.device, &self.config),
+                RenderTarget::Offscreen { texture } => *texture = create_offscreen_texture(&self.device, &self.config),
+            }
+            self.text.resize(new_size.width, new_size.height);
+            self.board3d.resize(&self.device, &self.config);
+            let staging = &mut self.camera_staging;
+            staging.camera.aspect = new_size.width as f32 / new_size.height as f32;
+            staging.controller.set_aspect(&staging.camera);
         }
     }
 
@@ -480,14 +597,28 @@ impl<'a> State<'a> {
                         physical_key,
                         ..
                     },..} => {
-                        match physical_key {
-                            PhysicalKey::Code(KeyCode::KeyW) if self.direction != Some(SnakeInputs::Down) => {self.direction = Some(SnakeInputs::Up);},
-                            PhysicalKey::Code(KeyCode::KeyA) if self.direction != Some(SnakeInputs::Right) => {self.direction = Some(SnakeInputs::Left);},
-                            PhysicalKey::Code(KeyCode::KeyS) if self.direction != Some(SnakeInputs::Up) => {self.direction = Some(SnakeInputs::Down);},
-                            PhysicalKey::Code(KeyCode::KeyD) if self.direction != Some(SnakeInputs::Left)=> {self.direction = Some(SnakeInputs::Right);},
-                            _=> {return false;}
-                        }
+                        self.press(*physical_key);
                     },
+            WindowEvent::MouseInput { state, button: MouseButton::Left, .. } => {
+                self.camera_staging.controller.mouse_button(*state == ElementState::Pressed);
+                return false;
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                self.camera_staging.controller.cursor_moved(position.x, position.y);
+                return false;
+            },
+            WindowEvent::CursorLeft { .. } => {
+                self.camera_staging.controller.cursor_left();
+                return false;
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.camera_staging.controller.scroll(*delta);
+                return false;
+            },
+            WindowEvent::Focused(false) => {
+                self.pause();
+                return false;
+            },
             _=> {
                 return false;
             }
@@ -554,116 +685,529 @@ impl<'a> State<'a> {
         
     }
 
-    pub fn update(&mut self) {
-        #[allow(clippy::single_match)]
-        match self.direction {
-            Some(d) => {
-                
-                
-                if self.last_updated.elapsed() >= Duration::from_millis(64) {
-                    self.directions.insert(0, d);
-                    if self.directions.len() > self.instances.len() {
-                        self.directions.pop();
-                    }
-                    let mut i = 0;
-                    let last_pos = self.instances.last().unwrap().position;
-                    while i < self.directions.len() {
-                        
-                        match self.directions[i] {
-                            SnakeInputs::Up => {
-
-                                self.instances[i].position.y += SPEED;
-                                if self.instances[i].position.y >= 1.2 {self.instances[i].position.y = -1.2}
-                            },
-                            SnakeInputs::Down => {
-                                self.instances[i].position.y -= SPEED;
-                                if self.instances[i].position.y <= -1.2 {self.instances[i].position.y = 1.2}
-                            },
-                            SnakeInputs::Left => {
-                                self.instances[i].position.x -= SPEED;
-                                if self.instances[i].position.x <= -1.2 {self.instances[i].position.x = 1.2}
-                            },
-                            SnakeInputs::Right => {
-                                self.instances[i].position.x += SPEED;
-                                if self.instances[i].position.x >= 1.2 {self.instances[i].position.x = -1.2}
-                            },
-                            SnakeInputs::Stay => {}
-                        }
-                        if i != 0 && self.instances[i].position == self.instances[0].position {
-                            self.direction = None;
-                            self.ended = true;
-
-                        }
-                        i += 1;
+    /// Acts on a key going down
+    fn press(&mut self, key: PhysicalKey) {
+        if let Some((player, direction)) = self.steering_key(key) {
+            self.steer(player, direction);
+            return;
+        }
+        match key {
+            PhysicalKey::Code(KeyCode::Space | KeyCode::Enter) if self.phase == Phase::Ready => {
+                self.start();
+            },
+            PhysicalKey::Code(KeyCode::KeyR) if matches!(self.phase, Phase::GameOver | Phase::HighScores) => {
+                self.restart();
+            },
+            PhysicalKey::Code(KeyCode::KeyH) if self.phase == Phase::GameOver && !self.is_remote() => {
+                self.phase = Phase::HighScores;
+            },
+            PhysicalKey::Code(KeyCode::KeyH) if self.phase == Phase::HighScores => self.phase = Phase::GameOver,
+            PhysicalKey::Code(KeyCode::KeyP | KeyCode::Pause) => self.toggle_pause(),
+            PhysicalKey::Code(KeyCode::KeyV) => self.toggle_3d(),
+            PhysicalKey::Code(KeyCode::KeyC) => {
+                let controller = &mut self.camera_staging.controller;
+                controller.set_preset(controller.preset.next());
+            },
+            PhysicalKey::Code(KeyCode::Space | KeyCode::Enter) if self.phase == Phase::Paused => {
+                self.resume();
+            },
+            _ => {},
+        }
+    }
 
+    /// The player and turn bound to `key`, for the players in this game
+    fn steering_key(&self, key: PhysicalKey) -> Option<(usize, SnakeInputs)> {
+        let PhysicalKey::Code(code) = key else {
+            return None;
+        };
+        let directions = [SnakeInputs::Up, SnakeInputs::Down, SnakeInputs::Left, SnakeInputs::Right];
+        PLAYER_KEYS[..self.game.snakes.len()].iter().enumerate().find_map(|(player, keys)| {
+            let i = keys.iter().position(|&k| k == code)?;
+            Some((player, directions[i]))
+        })
+    }
+
+    /// Hands a turn to `player`'s controller, leaving the ready screen if it
+    /// was taken or `player` is at the keyboard. Online, every steering key
+    /// turns this client's snake.
+    fn steer(&mut self, player: usize, direction: SnakeInputs) {
+        if let Some(remote) = &mut self.remote {
+            match (remote.player, &remote.rollback) {
+                // Played here, the turn goes out with the tick it lands on
+                (Some(player), Some(_)) => {
+                    self.controllers[player].steer(direction, &GameView::new(&self.game, player));
+                },
+                (Some(_), None) if !remote.closed => {
+                    if let Err(e) = remote.send(Signal::Input { direction }) {
+                        log::error!("{e:#}");
+                        remote.closed = true;
                     }
-                    //eprintln!("Apple pos: {:?}, Snake head pos: {:?}", self.apple_instances[0].position, self.instances[0].position);
-                    if (
-                        self.instances[0].position.x - self.apple_instances[0].position.x).abs() < 0.001
-                        && (self.instances[0].position.x - self.apple_instances[0].position.x).abs() > -0.001
-                        && (self.instances[0].position.y - self.apple_instances[0].position.y).abs() < 0.001
-                        && (self.instances[0].position.y - self.apple_instances[0].position.y).abs() > -0.001
-                      {
-                        self.instances.push(Instance { 
-                            position: last_pos, 
-                            rotation: self.instances.last().unwrap().rotation 
-                        });
-                        self.directions.push(SnakeInputs::Stay);
-                        //eprintln!("aple");
-                        //let step = Uniform::new(-9, 10);
-                        loop {
-                            //let mut rng = rand::thread_rng();
-                            //let choice = step.sample(&mut rng);
-                            //let x = choice as f32 * SPEED;
-                            //let choice = step.sample(&mut rng);
-                            //let y = choice as f32 * SPEED;
-
-                            let x = 0.2;
-                            let y = 0.2;
-                            
-                            if self.instances.iter().any(|i| {
-                                (i.position.x - x).abs() < 0.001
-                                &&  (i.position.x - x).abs() > -0.001
-                                &&  (i.position.y - y).abs() < 0.001
-                                &&  (i.position.y - y).abs() > -0.001
-                            }) {
-                                continue;
-                            } else {
-                                self.apple_instances[0].position = cgmath::Vector3 {x, y, z: 0.0};
-                                self.rebuild_apple_buffer();
-                                break;
-                            }
-                        
-                        }
-
-                        
-                        self.update_snake_length();
-                        
-                    }
+                },
+                _ => {},
+            }
+            return;
+        }
+        if matches!(self.phase, Phase::GameOver | Phase::Paused) || self.playback.is_some() || !self.game.snakes[player].alive {
+            return;
+        }
+        let taken = self.controllers[player].steer(direction, &GameView::new(&self.game, player));
+        // Any steering key starts, even one the snake is already heading in
+        if taken || self.game_config.controller(player) == ControllerKind::Keyboard {
+            self.start();
+        }
+    }
+
+    /// Stops the game where it is. Online games carry on regardless, so they
+    /// can't be paused.
+    pub fn pause(&mut self) {
+        if self.phase != Phase::Playing || self.is_remote() {
+            return;
+        }
+        self.phase = Phase::Paused;
+        self.timestep.pause(Instant::now());
+    }
+
+    pub fn resume(&mut self) {
+        if self.phase != Phase::Paused {
+            return;
+        }
+        self.phase = Phase::Playing;
+        self.timestep.resume(Instant::now());
+    }
+
+    fn toggle_pause(&mut self) {
+        match self.phase {
+            Phase::Paused => self.resume(),
+            _ => self.pause(),
+        }
+    }
+
+    /// Switches between flat sprites and 3D, tilting the camera for 3D if it
+    /// was looking straight down
+    fn toggle_3d(&mut self) {
+        self.game_config.three_d = !self.game_config.three_d;
+        let controller = &mut self.camera_staging.controller;
+        match controller.preset {
+            CameraPreset::TopDown if self.game_config.three_d => controller.set_preset(CameraPreset::ThreeQuarter),
+            CameraPreset::ThreeQuarter if !self.game_config.three_d => controller.set_preset(CameraPreset::TopDown),
+            _ => {},
+        }
+    }
+
+    fn start(&mut self) {
+        if self.phase != Phase::Ready || self.is_remote() {
+            return;
+        }
+        self.phase = Phase::Playing;
+        self.game.start();
+        // Make the first move on the very next frame
+        self.timestep.reset(Instant::now() - self.game_config.tick());
+    }
+
+    /// Starts a new game with a fresh seed unless the config fixes one,
+    /// reusing the window and GPU resources
+    pub fn restart(&mut self) {
+        // The server starts the next game itself
+        if self.is_remote() {
+            return;
+        }
+        self.game.reset(self.game_config.seed());
+        self.playback = None;
+        self.new_game();
+    }
+
+    /// Plays `replay` back from its first tick, ignoring the keyboard until it ends
+    pub fn play_replay(&mut self, replay: Replay) {
+        self.game = replay.new_game();
+        self.playback = Some(ReplayPlayer::new(replay));
+        self.new_game();
+        self.start();
+    }
+
+    /// Resets everything around `game` after it has been replaced
+    fn new_game(&mut self) {
+        self.phase = Phase::Ready;
+        self.timestep.step = self.game_config.tick();
+        self.controllers = create_controllers(&self.game_config, self.game.snakes.len());
+        self.recording = Replay::new(self.game.seed, self.game.rules.clone());
+        self.new_high_scores.clear();
+        self.clear_color = Color::BLACK;
+        self.previous_snakes = self.game.snakes.clone();
+        // Only refitted for a different board, so the view picked is kept
+        let bounds = camera_bounds(&self.game, self.game_config.cell_size);
+        if bounds != self.camera_staging.controller.bounds {
+            let head = self.followed_head();
+            self.camera_staging.controller.set_bounds(bounds, head);
+        }
+        self.instances = self.interpolated_instances();
+        self.apple_instances = apple_instances(&self.game, self.game_config.cell_size, self.ghost_cells);
+        self.update_snake_length();
+        self.rebuild_apple_buffer();
+        // A replay may have been recorded on a different level
+        self.wall_instances = wall_instances(&self.game, self.game_config.cell_size, self.ghost_cells);
+        self.wall_instances_buffer = create_wall_buffer(&self.device, &self.wall_instances);
+    }
+
+    fn end_game(&mut self) {
+        self.phase = Phase::GameOver;
+        self.clear_color = GAME_OVER_COLOR;
+        if self.playback.is_some() || self.is_remote() {
+            return;
+        }
+        match (self.game.snakes.as_slice(), self.game.winner) {
+            ([snake], _) => log::info!("Game over with a score of {}, press R to play again", snake.score),
+            (_, Some(winner)) => log::info!("Player {} wins, press R to play again", winner + 1),
+            (_, None) => log::info!("Draw, press R to play again"),
+        }
+        if let Some(path) = &self.record_to {
+            match self.recording.save(path) {
+                Ok(()) => log::info!("Saved replay to {}", path.display()),
+                Err(e) => log::error!("{e:#}"),
+            }
+        }
+        self.record_high_scores();
+    }
+
+    /// Puts the scores of the snakes steered from the keyboard on the high
+    /// score table if they are good enough, and saves it when playing in a
+    /// window
+    fn record_high_scores(&mut self) {
+        let players = self.game.snakes.len();
+        let mode = if players == 1 { Mode::Solo } else { Mode::Versus(players) };
+        let date = crate::scores::now();
+        let mut scores = (0..players)
+            .filter(|&player| self.game_config.controller(player) == ControllerKind::Keyboard)
+            .map(|player| HighScore {
+                name: if players == 1 { self.game_config.name.clone() } else { format!("P{}", player + 1) },
+                score: self.game.snakes[player].score,
+                date,
+                seed: self.game.seed,
+                mode,
+                board: self.game.rules.board,
+                edges: self.game.rules.edges,
+                tick_ms: self.game_config.tick_ms,
+                layout: crate::scores::layout_name(&self.game.rules),
+            })
+            .collect::<Vec<_>>();
+        // Best first, so later ones can't push earlier ones down the table
+        scores.sort_by_key(|score| std::cmp::Reverse(score.score));
+        self.new_high_scores = scores.into_iter().filter_map(|score| self.high_scores.insert(score)).collect();
+        if self.new_high_scores.is_empty() || self.window().is_none() {
+            return;
+        }
+        match self.high_scores.save() {
+            Ok(()) => log::info!("New high score, ranked {}", self.new_high_scores[0] + 1),
+            Err(e) => log::error!("{e:#}"),
+        }
+    }
 
-                    self.last_updated = Instant::now();
+    /// Advances the game by one tick, taking the turn from the replay being
+    /// played or the keyboard
+    pub fn tick(&mut self) {
+        self.previous_snakes = self.game.snakes.clone();
+        let inputs = match &mut self.playback {
+            Some(player) => player.next_input(),
+            None => Some(
+                self.controllers
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(player, controller)| {
+                        let input = controller.next_input(&GameView::new(&self.game, player));
+                        Some(input).filter(|&input| input != SnakeInputs::Stay)
+                    })
+                    .collect::<Vec<_>>()
+            ),
+        };
+        let outcome = match inputs {
+            Some(inputs) => {
+                self.recording.record(&inputs);
+                self.game.step(&inputs)
+            },
+            // The replay ran out before the game ended
+            None => StepOutcome::Died,
+        };
+        match outcome {
+            StepOutcome::AteApple => {
+                self.instances = self.interpolated_instances();
+                self.apple_instances = apple_instances(&self.game, self.game_config.cell_size, self.ghost_cells);
+                self.rebuild_apple_buffer();
+                self.update_snake_length();
+            },
+            StepOutcome::Moved => {
+                self.instances = self.interpolated_instances();
+            },
+            StepOutcome::Died | StepOutcome::Won => {
+                self.instances = self.interpolated_instances();
+                self.apple_instances = apple_instances(&self.game, self.game_config.cell_size, self.ghost_cells);
+                self.rebuild_apple_buffer();
+                self.update_snake_length();
+                self.end_game();
+            },
+            StepOutcome::Idle => {}
+        }
+    }
+
+    pub fn update(&mut self) {
+        if self.remote.is_some() {
+            self.poll_remote();
+            self.tick_lockstep();
+            self.prepare();
+            return;
+        }
+        if self.phase == Phase::Paused {
+            self.prepare();
+            return;
+        }
+        if self.phase != Phase::Playing {
+            self.timestep.reset(Instant::now());
+            self.prepare();
+            return;
+        }
+        let due = self.timestep.advance(Instant::now());
+        if due.dropped > 0 {
+            log::warn!("Fell {} ticks behind, skipping them", due.dropped);
+        }
+        for _ in 0..due.ticks {
+            // The game may end partway through catching up
+            if self.phase != Phase::Playing {
+                break;
+            }
+            self.tick();
+        }
+        self.prepare();
+    }
+
+    fn is_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// Follows the game on `connection` from now on instead of playing locally
+    pub fn connect(&mut self, connection: Connection) {
+        self.playback = None;
+        self.remote = Some(Remote::new(connection, self.game_config.network));
+        self.new_game();
+    }
+
+    /// Applies everything the server sent since the last frame
+    fn poll_remote(&mut self) {
+        let Some(remote) = &mut self.remote else {
+            return;
+        };
+        if remote.closed {
+            return;
+        }
+        match remote.poll() {
+            Ok(updates) => updates.into_iter().for_each(|update| self.apply_update(update)),
+            Err(e) => {
+                log::error!("{e:#}");
+                remote.closed = true;
+            },
+        }
+    }
+
+    fn apply_update(&mut self, update: Update) {
+        let Some(remote) = &mut self.remote else {
+            return;
+        };
+        match update {
+            Update::Welcome { player, rules, tick_ms } => {
+                remote.player = player;
+                remote.rollback = None;
+                self.game_config.tick_ms = tick_ms;
+                self.game = SnakeState::new(rules, 0);
+                self.new_game();
+            },
+            Update::Waiting { joined, needed } => remote.waiting = Some((joined, needed)),
+            // Players in a lockstep game run it themselves
+            Update::Snapshot(_) if remote.rollback.is_some() => {},
+            Update::Snapshot(snapshot) => {
+                remote.waiting = None;
+                let new_round = snapshot.ticks == 0 && self.phase != Phase::Ready;
+                self.previous_snakes = self.game.snakes.clone();
+                self.timestep.reset(Instant::now());
+                snapshot.apply(&mut self.game);
+                if new_round {
+                    self.new_game();
+                }
+                if self.game.ticks > 0 && self.phase == Phase::Ready {
+                    self.phase = Phase::Playing;
+                }
+                self.show_game();
+            },
+            Update::Start { seed, netcode } => {
+                remote.waiting = None;
+                self.game = SnakeState::new(self.game.rules.clone(), seed);
+                self.game.start();
+                remote.rollback = remote.player.map(|player| Rollback::new(self.game.clone(), Some(player), netcode));
+                self.previous_snakes = self.game.snakes.clone();
+                self.phase = Phase::Playing;
+                self.timestep.reset(Instant::now());
+                self.show_game();
+            },
+            Update::Turns { player, tick, turns } => {
+                if let Some(rollback) = &mut remote.rollback {
+                    rollback.receive(player, tick, &turns);
+                    self.game = rollback.game().clone();
+                    self.show_game();
                 }
             },
-            None => {
+            Update::GameOver { winner, .. } => {
+                remote.rollback = None;
+                self.game.winner = winner;
+                self.game.ended = true;
+                self.end_game();
+            },
+            Update::Rejected { reason } => log::warn!("The server said: {reason}"),
+        }
+    }
 
+    /// Plays the lockstep ticks that are due, unless the other players'
+    /// turns are too far behind, and sends the local turns
+    fn tick_lockstep(&mut self) {
+        let Some(remote) = &mut self.remote else {
+            return;
+        };
+        let Some(player) = remote.player.filter(|_| remote.rollback.is_some() && !remote.closed) else {
+            return;
+        };
+        let due = self.timestep.advance(Instant::now());
+        for ran in 0..due.ticks {
+            let Some(rollback) = remote.rollback.as_mut().filter(|rollback| rollback.can_advance()) else {
+                // Still waiting on the others, these run once their turns are in
+                self.timestep.defer(due.ticks - ran);
+                break;
+            };
+            let turn = self.controllers[player].next_input(&GameView::new(rollback.game(), player));
+            rollback.advance(Some(turn).filter(|&turn| turn != SnakeInputs::Stay));
+            self.previous_snakes = std::mem::replace(&mut self.game, rollback.game().clone()).snakes;
+            let (tick, turns) = rollback.recent_turns();
+            if let Err(e) = remote.send(Signal::Turns { tick, turns }) {
+                log::error!("{e:#}");
+                remote.closed = true;
+                break;
             }
         }
-        
+        if due.ticks > 0 {
+            self.show_game();
+        }
+    }
 
-        self.rebuild_instance_buffer();
-        //self.camera_uniform.update_view_proj(&self.camera);
+    /// Rebuilds everything drawn from the game after it was replaced
+    fn show_game(&mut self) {
+        self.instances = self.interpolated_instances();
+        self.apple_instances = apple_instances(&self.game, self.game_config.cell_size, self.ghost_cells);
+        self.update_snake_length();
+        self.rebuild_apple_buffer();
+    }
+
+    /// How far along it is from the last tick to the next, from 0 to 1
+    fn tick_progress(&self) -> f32 {
+        if !matches!(self.phase, Phase::Playing | Phase::Paused) {
+            return 1.0;
+        }
+        self.timestep.alpha(Instant::now())
+    }
+
+    /// Where the head the follow camera keeps in view is drawn this frame:
+    /// this client's snake online, otherwise the first steered from the
+    /// keyboard
+    fn followed_head(&self) -> cgmath::Point3<f32> {
+        let player = match &self.remote {
+            Some(remote) => remote.player.unwrap_or(0),
+            None => (0..self.game.snakes.len())
+                .find(|&player| self.game_config.controller(player) == ControllerKind::Keyboard)
+                .unwrap_or(0),
+        };
+        let Some(snake) = self.game.snakes.get(player) else {
+            return cgmath::Point3::origin();
+        };
+        let board = &self.game.rules.board;
+        let cell_size = self.game_config.cell_size;
+        let head = snake.body[0];
+        let position = match self.previous_snakes.get(player).map(|before| before.body[0]) {
+            Some(from) => match grid_step(board, from, head) {
+                Some(step) => grid_to_world(board, cell_size, from) + step * (self.tick_progress() * cell_size),
+                None => grid_to_world(board, cell_size, head),
+            },
+            None => grid_to_world(board, cell_size, head),
+        };
+        cgmath::Point3::from_vec(position)
+    }
+
+    /// Redraws the apples and walls with as many ghost cells as the camera
+    /// now needs
+    fn update_ghosts(&mut self) {
+        let board = self.game.rules.board;
+        let cell_size = self.game_config.cell_size;
+        let ghost_cells = self.camera_staging.controller.ghost_margin()
+            .map_or(0, |margin| (margin / cell_size).ceil() as i32 + 1)
+            .min(board.width.max(board.height));
+        if ghost_cells == self.ghost_cells {
+            return;
+        }
+        self.ghost_cells = ghost_cells;
+        self.apple_instances = apple_instances(&self.game, cell_size, ghost_cells);
+        self.rebuild_apple_buffer();
+        self.wall_instances = wall_instances(&self.game, cell_size, ghost_cells);
+        self.wall_instances_buffer = create_wall_buffer(&self.device, &self.wall_instances);
+    }
+
+    /// The snakes as they are drawn this frame, partway into their next cell
+    fn interpolated_instances(&self) -> Vec<Instance> {
+        let cell_size = self.game_config.cell_size;
+        let mut instances = snake_instances(&self.game, &self.previous_snakes, self.tick_progress(), cell_size);
+        add_ghosts(&mut instances, &self.game.rules.board, cell_size, self.ghost_cells);
+        instances
+    }
+
+    /// Uploads the instances, HUD and camera for the next frame
+    pub fn prepare(&mut self) {
+        let head = self.followed_head();
+        self.camera_staging.controller.update(Instant::now(), head);
         self.camera_staging.update_camera(&mut self.camera_uniform);
-        //self.camera_staging.update_camera_pitch(&mut self.camera_uniform);
-        //self.camera_staging.update_camera_yaw(&mut self.camera_uniform);
+        self.update_ghosts();
+        self.instances = self.interpolated_instances();
+        self.rebuild_instance_buffer();
+        if self.game_config.three_d {
+            let instances = self.wall_instances.iter().chain(&self.instances).chain(&self.apple_instances);
+            let board = self.game.rules.board;
+            self.board3d.prepare(&self.device, &self.queue, instances, &board, self.game_config.cell_size, self.ghost_cells);
+        }
+        self.update_hud();
         self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
-
-        
     }
 
 pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-    let output = self.surface.get_current_texture()?;
-    let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+    match &self.target {
+        RenderTarget::Window { surface, .. } => {
+            let output = surface.get_current_texture()?;
+            let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+            self.draw(&view);
+            output.present();
+        },
+        RenderTarget::Offscreen { texture } => {
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            self.draw(&view);
+        },
+    }
+    Ok(())
+}
 
+/// Draws the current frame into `view` and waits for it to finish.
+///
+/// Only works for states made with `new_headless`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn render_to_image(&mut self) -> anyhow::Result<image::RgbaImage> {
+    self.prepare();
+    let RenderTarget::Offscreen { texture } = &self.target else {
+        anyhow::bail!("Only headless states can be read back");
+    };
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    self.draw(&view);
+    read_texture(&self.device, &self.queue, texture)
+}
+
+fn draw(&self, view: &wgpu::TextureView) {
     let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
         label: Some("Render Encoder"),
     });
@@ -672,46 +1216,215 @@ pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Render Pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(self.clear_color),
                     store: wgpu::StoreOp::Store,
                 },
             })],
-            depth_stencil_attachment: None,
+            depth_stencil_attachment: self.game_config.three_d.then_some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.board3d.depth_texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
             timestamp_writes: None,
             occlusion_query_set: None,
         });
 
-        render_pass.set_pipeline(&self.render_pipeline);
-        render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-        render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        if self.game_config.three_d {
+            self.board3d.draw(&mut render_pass, &self.diffuse_bind_group, &self.camera_bind_group);
+        } else {
+            self.draw_flat(&mut render_pass);
+        }
+    }
+
+    {
+        // The HUD gets a pass of its own, as its pipeline has no depth
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("HUD Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        self.text.draw(&mut render_pass);
+    }
 
-        // Snake rendering
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+    // Submit the commands
+    self.queue.submit(std::iter::once(encoder.finish()));
+}
 
-        // UPDATED!
-        render_pass.draw_indexed(0..self.num_indices, 0, 0..self.instances.len() as _);
+/// Draws the walls, snakes and apples as flat sprites
+fn draw_flat<'pass>(&'pass self, render_pass: &mut wgpu::RenderPass<'pass>) {
+    render_pass.set_pipeline(&self.render_pipeline);
+    render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+    render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
 
-        // Apple rendering
-        render_pass.set_vertex_buffer(0, self.apple_vertex_buffer.slice(..));
-        render_pass.set_vertex_buffer(1, self.apple_instances_buffer.slice(..));
-        render_pass.draw_indexed(0..self.num_indices, 0, 0..self.apple_instances.len() as _);
+    render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+    render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
 
+    // Wall rendering, an empty buffer can't be bound
+    if !self.wall_instances.is_empty() {
+        render_pass.set_vertex_buffer(1, self.wall_instances_buffer.slice(..));
+        render_pass.draw_indexed(0..self.num_indices, 0, 0..self.wall_instances.len() as _);
     }
 
-    // Submit the commands
-    self.queue.submit(std::iter::once(encoder.finish()));
-    output.present();
-    //std::thread::sleep(Duration::from_millis(100));
-    Ok(())
+    // Snake rendering
+    render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+
+    // UPDATED!
+    render_pass.draw_indexed(0..self.num_indices, 0, 0..self.instances.len() as _);
+
+    // Apple rendering
+    render_pass.set_vertex_buffer(1, self.apple_instances_buffer.slice(..));
+    render_pass.draw_indexed(0..self.num_indices, 0, 0..self.apple_instances.len() as _);
 }
 
+    /// Lays out the score line and any message for the current phase
+    fn update_hud(&mut self) {
+        let elapsed = self.game.elapsed(self.game_config.tick()).as_secs();
+        let time = format!("TIME {}:{:02}", elapsed / 60, elapsed % 60);
+        let margin = TextRenderer::line_height(HUD_SCALE) / 2.0;
+        let middle = self.size.height as f32 / 2.0;
+        self.text.clear();
+        if let [snake] = self.game.snakes.as_slice() {
+            let hud = format!("SCORE {}  LENGTH {}  {time}", snake.score, snake.length());
+            self.text.push_text(&hud, margin, margin, HUD_SCALE, HUD_COLOR);
+        } else {
+            // One score per player in their own colour, then the clock
+            let mut x = margin;
+            for (player, snake) in self.game.snakes.iter().enumerate() {
+                let score = format!("P{} {}  ", player + 1, snake.score);
+                self.text.push_text(&score, x, margin, HUD_SCALE, PLAYER_COLORS[player]);
+                x += TextRenderer::text_width(&score, HUD_SCALE);
+            }
+            self.text.push_text(&time, x, margin, HUD_SCALE, HUD_COLOR);
+        }
+        let second_line = margin + TextRenderer::line_height(HUD_SCALE);
+        if self.playback.is_some() {
+            self.text.push_text("REPLAY", margin, second_line, HUD_SCALE, HUD_COLOR);
+        }
+        if let Some(remote) = &self.remote {
+            match remote.player {
+                Some(player) => {
+                    let you = format!("YOU ARE P{}", player + 1);
+                    self.text.push_text(&you, margin, second_line, HUD_SCALE, PLAYER_COLORS[player]);
+                },
+                None => self.text.push_text("WATCHING", margin, second_line, HUD_SCALE, HUD_COLOR),
+            }
+            let message = match (remote.closed, remote.waiting, self.phase) {
+                (true, _, _) => Some("DISCONNECTED".to_string()),
+                (_, Some((joined, needed)), _) => Some(format!("WAITING FOR PLAYERS {joined}/{needed}")),
+                (_, _, Phase::Ready) => Some("GET READY".to_string()),
+                _ => None,
+            };
+            if let Some(message) = message {
+                self.text.push_centered(&message, middle, HUD_SCALE, HUD_COLOR);
+                self.text.prepare(&self.device, &self.queue);
+                return;
+            }
+        }
+        match self.phase {
+            Phase::Ready => {
+                let keyboard = (0..self.game.snakes.len())
+                    .filter(|&player| self.game_config.controller(player) == ControllerKind::Keyboard)
+                    .count();
+                let prompt = match (self.game.snakes.len(), keyboard) {
+                    (_, 0) => "PRESS SPACE TO START",
+                    (1, _) => "PRESS SPACE OR WASD TO START",
+                    _ => "PRESS SPACE OR STEER TO START",
+                };
+                self.text.push_centered(prompt, middle, HUD_SCALE, HUD_COLOR);
+            },
+            Phase::GameOver => {
+                let (title, color) = match (self.game.snakes.len(), self.game.winner) {
+                    _ if self.playback.is_some() && !self.game.ended => ("END OF REPLAY".to_string(), HUD_COLOR),
+                    (1, _) if self.game.won => ("YOU WIN!".to_string(), HUD_COLOR),
+                    (1, _) => ("GAME OVER".to_string(), HUD_COLOR),
+                    (_, Some(winner)) => (format!("PLAYER {} WINS!", winner + 1), PLAYER_COLORS[winner]),
+                    (_, None) => ("DRAW".to_string(), HUD_COLOR),
+                };
+                self.text.push_centered(&title, middle - TextRenderer::line_height(HUD_SCALE * 2.0), HUD_SCALE * 2.0, color);
+                let hint = if self.is_remote() { "NEXT ROUND STARTS SOON" } else { "PRESS R TO PLAY AGAIN" };
+                self.text.push_centered(hint, middle, HUD_SCALE, HUD_COLOR);
+                if !self.is_remote() {
+                    let below = middle + TextRenderer::line_height(HUD_SCALE);
+                    self.text.push_centered("OR H FOR HIGH SCORES", below, HUD_SCALE, HUD_COLOR);
+                }
+                if let Some(&rank) = self.new_high_scores.first() {
+                    let message = format!("NEW HIGH SCORE! RANK {}", rank + 1);
+                    let above = middle - TextRenderer::line_height(HUD_SCALE * 2.0) * 2.0;
+                    self.text.push_centered(&message, above, HUD_SCALE, NEW_SCORE_COLOR);
+                }
+            },
+            Phase::HighScores => self.push_high_scores(),
+            Phase::Paused => {
+                let (width, height) = (self.size.width as f32, self.size.height as f32);
+                self.text.push_rect([0.0, 0.0], [width, height], PAUSE_DIM);
+                self.text.push_centered("PAUSED", middle - TextRenderer::line_height(HUD_SCALE * 2.0), HUD_SCALE * 2.0, HUD_COLOR);
+                self.text.push_centered("PRESS P TO RESUME", middle, HUD_SCALE, HUD_COLOR);
+            },
+            Phase::Playing => {}
+        }
+        self.text.prepare(&self.device, &self.queue);
+    }
+
+    /// Lays the high score table over the board
+    fn push_high_scores(&mut self) {
+        let (width, height) = (self.size.width as f32, self.size.height as f32);
+        self.text.push_rect([0.0, 0.0], [width, height], PAUSE_DIM);
+        let line = TextRenderer::line_height(TABLE_SCALE);
+        let mut y = line * 3.0;
+        self.text.push_centered("HIGH SCORES", y, HUD_SCALE * 2.0, HUD_COLOR);
+        y += TextRenderer::line_height(HUD_SCALE * 2.0) + line;
+        let rows = self.high_scores.scores.iter().enumerate().map(|(rank, entry)| {
+            let name = entry.name.chars().take(8).collect::<String>();
+            let edges = match entry.edges {
+                Edges::Wrap => "",
+                Edges::Solid => " SOLID",
+            };
+            let layout = if entry.layout.is_empty() { "?" } else { &entry.layout };
+            format!(
+                "{:>2}. {name:<8} {:>5}  {}  {:<4} {:<11} {}X{}{edges}",
+                rank + 1,
+                entry.score,
+                entry.day(),
+                entry.mode.name(),
+                layout,
+                entry.board.width,
+                entry.board.height,
+            )
+        }).collect::<Vec<_>>();
+        if rows.is_empty() {
+            self.text.push_centered("NO SCORES YET", y, TABLE_SCALE, HUD_COLOR);
+        }
+        // Lined up on the left edge of the widest row, centred as a block
+        let widest = rows.iter().map(|row| TextRenderer::text_width(row, TABLE_SCALE)).fold(0.0, f32::max);
+        let x = ((width - widest) / 2.0).max(0.0);
+        for (rank, row) in rows.iter().enumerate() {
+            let color = if self.new_high_scores.contains(&rank) { NEW_SCORE_COLOR } else { HUD_COLOR };
+            self.text.push_text(row, x, y, TABLE_SCALE, color);
+            y += line;
+        }
+        let bottom = height - line * 3.0;
+        self.text.push_centered("PRESS H TO GO BACK, R TO PLAY AGAIN", bottom, TABLE_SCALE, HUD_COLOR);
+    }
+
     pub fn rebuild_instance_buffer(&mut self) {
-        
+        if self.instance_buffer.size() < (self.instances.len() * std::mem::size_of::<InstanceRaw>()) as u64 {
+            return self.update_snake_length();
+        }
 
         let instance_data = self.instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
         
@@ -740,7 +1453,17 @@ pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
 
     pub fn rebuild_apple_buffer(&mut self) {
         let instance_data = self.apple_instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        
+        // Ghost copies can outgrow it
+        if self.apple_instances_buffer.size() < (instance_data.len() * std::mem::size_of::<InstanceRaw>()) as u64 {
+            self.apple_instances_buffer = self.device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Apple instance Buffer"),
+                    contents: bytemuck::cast_slice(&instance_data),
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                }
+            );
+            return;
+        }
 
         
         
@@ -754,6 +1477,272 @@ pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
 }
 
 
+async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+    adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            required_features: wgpu::Features::empty(),
+            // WebGL doesn't support all of wgpu's features, so if
+            // we're building for the web, we'll have to disable some.
+            required_limits: if cfg!(target_arch = "wasm32") {
+                wgpu::Limits::downlevel_webgl2_defaults()
+            } else {
+                wgpu::Limits::default()
+            },
+            label: None,
+            memory_hints: Default::default(),
+        },
+        None, // Trace path
+    ).await
+}
+
+/// One controller per snake, as `game_config` assigns them
+fn create_controllers(game_config: &GameConfig, players: usize) -> Vec<Box<dyn Controller>> {
+    (0..players).map(|player| game_config.controller(player).create()).collect()
+}
+
+fn create_offscreen_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen target"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: config.usage,
+        view_formats: &[],
+    })
+}
+
+/// Copies an RGBA8 texture back to the CPU, blocking until the GPU is done
+#[cfg(not(target_arch = "wasm32"))]
+fn read_texture(device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) -> anyhow::Result<image::RgbaImage> {
+    let (width, height) = (texture.width(), texture.height());
+    // Rows in the copy have to be padded to a multiple of 256 bytes
+    let unpadded_bytes_per_row = 4 * width;
+    let bytes_per_row = unpadded_bytes_per_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Buffer"),
+        size: (bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        texture.size(),
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| sender.send(result).unwrap());
+    device.poll(wgpu::Maintain::Wait);
+    receiver.recv()?.context("Couldn't map the readback buffer")?;
+
+    let data = slice.get_mapped_range();
+    let pixels = data
+        .chunks(bytes_per_row as usize)
+        .flat_map(|row| &row[..unpadded_bytes_per_row as usize])
+        .copied()
+        .collect();
+    drop(data);
+    buffer.unmap();
+    image::RgbaImage::from_raw(width, height, pixels).context("Readback has the wrong size")
+}
+
+/// Converts a board cell to the world position of its centre, with the board
+/// centred on the origin
+fn grid_to_world(board: &Board, cell_size: f32, pos: GridPos) -> cgmath::Vector3<f32> {
+    cgmath::Vector3 {
+        x: (pos.x as f32 - (board.width - 1) as f32 / 2.0) * cell_size,
+        y: (pos.y as f32 - (board.height - 1) as f32 / 2.0) * cell_size,
+        z: 0.0,
+    }
+}
+
+/// Rotation turning a sprite drawn facing right to face `direction`
+fn facing(direction: SnakeInputs) -> cgmath::Quaternion<f32> {
+    let angle = match direction {
+        SnakeInputs::Right | SnakeInputs::Stay => 0.0,
+        SnakeInputs::Up => 90.0,
+        SnakeInputs::Left => 180.0,
+        SnakeInputs::Down => 270.0,
+    };
+    cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(angle))
+}
+
+/// Picks the atlas tile and rotation for segment `i` of a snake, from the
+/// direction it moved in and the direction the segment ahead of it left in
+fn segment_sprite(directions: &[SnakeInputs], i: usize) -> (Sprite, cgmath::Quaternion<f32>) {
+    if i == 0 {
+        return (Sprite::Head, facing(directions[0]));
+    }
+    let out = directions[i - 1];
+    if i == directions.len() - 1 {
+        return (Sprite::Tail, facing(out));
+    }
+    let entered = directions[i];
+    if entered == out {
+        return (Sprite::Body, facing(out));
+    }
+    // The corner tile joins the left and top edges. Turn it a quarter at a
+    // time until it joins the edge the segment was entered through and the
+    // one it is left through.
+    let rotate = |d: SnakeInputs| match d {
+        SnakeInputs::Right => SnakeInputs::Up,
+        SnakeInputs::Up => SnakeInputs::Left,
+        SnakeInputs::Left => SnakeInputs::Down,
+        SnakeInputs::Down => SnakeInputs::Right,
+        SnakeInputs::Stay => SnakeInputs::Stay,
+    };
+    let wanted = [entered.opposite(), out];
+    let mut edges = [SnakeInputs::Left, SnakeInputs::Up];
+    for quarter in 0..4 {
+        if wanted.contains(&edges[0]) && wanted.contains(&edges[1]) {
+            let rotation = cgmath::Deg(90.0 * quarter as f32);
+            return (Sprite::Corner, cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), rotation));
+        }
+        edges = edges.map(rotate);
+    }
+    (Sprite::Body, facing(out))
+}
+
+/// The move from `from` to `to` in cells, taking the short way round a
+/// wrapping board. `None` unless they are next to each other.
+fn grid_step(board: &Board, from: GridPos, to: GridPos) -> Option<cgmath::Vector3<f32>> {
+    let shortest = |delta: i32, size: i32| match delta {
+        d if d > 1 => d - size,
+        d if d < -1 => d + size,
+        d => d,
+    };
+    let (dx, dy) = (shortest(to.x - from.x, board.width), shortest(to.y - from.y, board.height));
+    (dx.abs() + dy.abs() <= 1).then(|| cgmath::Vector3::new(dx as f32, dy as f32, 0.0))
+}
+
+/// Every segment of every snake, each player in their own atlas row.
+///
+/// Only the ends of a snake move between ticks, the segments in between are
+/// already where they will stay. The head and tail are drawn `alpha` of the
+/// way from their cells in `previous`, turning as they round a corner. Ends
+/// leaving through a wrapping edge slide off it rather than across the board.
+fn snake_instances(game: &SnakeState, previous: &[Snake], alpha: f32, cell_size: f32) -> Vec<Instance> {
+    let board = &game.rules.board;
+    let mut instances = Vec::new();
+    for (player, snake) in game.snakes.iter().enumerate() {
+        let instance = |pos, (sprite, rotation): (Sprite, cgmath::Quaternion<f32>)| Instance {
+            sprite,
+            position: grid_to_world(board, cell_size, pos),
+            rotation,
+            atlas_rect: sprite.atlas_rect(player as u32),
+        };
+        let tail = snake.body.len() - 1;
+        // Tail first so the head ends up on top
+        for (i, &pos) in snake.body.iter().enumerate().rev() {
+            let mut segment = instance(pos, segment_sprite(&snake.directions, i));
+            let end = match previous.get(player) {
+                Some(before) if i == 0 => Some((before, 0)),
+                Some(before) if i == tail => Some((before, before.body.len() - 1)),
+                _ => None,
+            };
+            if let Some((before, j)) = end {
+                let from = before.body[j];
+                if let Some(step) = grid_step(board, from, pos) {
+                    if i == tail && j > 0 && from != pos {
+                        // The cell the tail is moving into stays covered until it gets there
+                        instances.push(instance(pos, segment_sprite(&before.directions, j - 1)));
+                    }
+                    segment.position = grid_to_world(board, cell_size, from) + step * (alpha * cell_size);
+                    segment.rotation = segment_sprite(&before.directions, j).1.slerp(segment.rotation, alpha);
+                }
+            }
+            instances.push(segment);
+        }
+    }
+    instances
+}
+
+fn wall_instances(game: &SnakeState, cell_size: f32, ghost_cells: i32) -> Vec<Instance> {
+    let walls = game.rules.walls.iter().map(|&pos| (pos, Sprite::Wall));
+    let obstacles = game.rules.obstacles.iter().map(|&pos| (pos, Sprite::Obstacle));
+    let mut instances = walls.chain(obstacles).map(|(pos, sprite)| Instance {
+        sprite,
+        position: grid_to_world(&game.rules.board, cell_size, pos),
+        rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0)),
+        atlas_rect: sprite.atlas_rect(0),
+    }).collect();
+    add_ghosts(&mut instances, &game.rules.board, cell_size, ghost_cells);
+    instances
+}
+
+/// Copies `instances` a whole board over in every direction, keeping the
+/// copies that land within `cells` of the board's edges. A wrapping board
+/// seen past its edge then carries on into the other side.
+fn add_ghosts(instances: &mut Vec<Instance>, board: &Board, cell_size: f32, cells: i32) {
+    if cells <= 0 {
+        return;
+    }
+    let (width, height) = (board.width as f32 * cell_size, board.height as f32 * cell_size);
+    let reach = cgmath::Vector2::new(width / 2.0, height / 2.0) + cgmath::Vector2::new(1.0, 1.0) * (cells as f32 * cell_size);
+    for i in 0..instances.len() {
+        for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let mut ghost = instances[i];
+            ghost.position += cgmath::Vector3::new(dx as f32 * width, dy as f32 * height, 0.0);
+            if ghost.position.x.abs() <= reach.x && ghost.position.y.abs() <= reach.y {
+                instances.push(ghost);
+            }
+        }
+    }
+}
+
+/// The board as the camera needs to know it
+fn camera_bounds(game: &SnakeState, cell_size: f32) -> Bounds {
+    let board = game.rules.board;
+    Bounds {
+        half_size: cgmath::Vector2::new(board.width as f32, board.height as f32) * (cell_size / 2.0),
+        cell_size,
+        wraps: game.rules.edges == Edges::Wrap,
+    }
+}
+
+/// Walls never change during a game, so their buffer is only made once per game
+fn create_wall_buffer(device: &wgpu::Device, instances: &[Instance]) -> wgpu::Buffer {
+    let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+    device.create_buffer_init(
+        &wgpu::util::BufferInitDescriptor {
+            label: Some("Wall instance Buffer"),
+            contents: bytemuck::cast_slice(&instance_data),
+            usage: wgpu::BufferUsages::VERTEX,
+        }
+    )
+}
+
+fn apple_instances(game: &SnakeState, cell_size: f32, ghost_cells: i32) -> Vec<Instance> {
+    let mut instances = game.apple.iter().map(|&pos| Instance {
+        sprite: Sprite::Apple,
+        position: grid_to_world(&game.rules.board, cell_size, pos),
+        rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0)),
+        atlas_rect: Sprite::Apple.atlas_rect(0),
+    }).collect();
+    add_ghosts(&mut instances, &game.rules.board, cell_size, ghost_cells);
+    instances
+}
+
+
 impl Vertex {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
@@ -778,3 +1767,40 @@ impl Vertex {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headless(game_config: GameConfig) -> State<'static> {
+        pollster::block_on(State::new_headless(game_config)).unwrap()
+    }
+
+    #[test]
+    fn a_fresh_game_takes_steering_keys() {
+        let mut state = headless(GameConfig { players: 2, seed: Some(1), ..GameConfig::default() });
+        state.press(PhysicalKey::Code(KeyCode::KeyW));
+        state.press(PhysicalKey::Code(KeyCode::ArrowDown));
+        assert_eq!(state.phase, Phase::Playing);
+        state.tick();
+        assert_eq!(state.game.snakes[0].heading(), SnakeInputs::Up);
+        assert_eq!(state.game.snakes[1].heading(), SnakeInputs::Down);
+    }
+
+    #[test]
+    fn a_fresh_game_is_steered_by_its_bots() {
+        let mut state = headless(GameConfig {
+            edges: Edges::Solid,
+            controllers: vec![ControllerKind::Pathfinder],
+            seed: Some(1),
+            ..GameConfig::default()
+        });
+        state.press(PhysicalKey::Code(KeyCode::Space));
+        // Heading straight on, the snake would hit the edge well before this
+        for _ in 0..40 {
+            state.tick();
+        }
+        assert_eq!(state.phase, Phase::Playing);
+        assert!(state.game.snakes[0].score > 0);
+    }
+}
@@ -0,0 +1,423 @@
+use wgpu::util::DeviceExt;
+
+use crate::texture;
+
+/// Size of one glyph in the font, in font pixels
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+/// Glyphs are laid out in the atlas with one pixel of padding right and below
+const CELL_WIDTH: u32 = GLYPH_WIDTH + 1;
+const CELL_HEIGHT: u32 = GLYPH_HEIGHT + 1;
+const ATLAS_COLUMNS: u32 = 16;
+
+/// Glyphs the instance buffer starts with room for, it grows when a frame
+/// needs more
+const INITIAL_GLYPHS: usize = 512;
+
+/// A 5x7 pixel font, one byte per row with the leftmost pixel in bit 4.
+/// Lowercase letters are drawn with their uppercase glyph.
+const FONT: &[(char, [u8; 7])] = &[
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('0', [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E]),
+    ('1', [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('2', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+    ('3', [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+    ('4', [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+    ('5', [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+    ('6', [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+    ('7', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+    ('9', [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+    ('A', [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('B', [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E]),
+    ('C', [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+    ('D', [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C]),
+    ('E', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F]),
+    ('F', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10]),
+    ('G', [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F]),
+    ('H', [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('I', [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F]),
+    ('M', [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('O', [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('P', [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10]),
+    ('Q', [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D]),
+    ('R', [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11]),
+    ('S', [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E]),
+    ('T', [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A]),
+    ('X', [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04]),
+    ('Z', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F]),
+    (':', [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C]),
+    (',', [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08]),
+    ('-', [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00]),
+    ('!', [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04]),
+    ('?', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04]),
+    ('/', [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00]),
+    // Solid, for `push_rect`
+    ('█', [0x1F, 0x1F, 0x1F, 0x1F, 0x1F, 0x1F, 0x1F]),
+];
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct QuadVertex {
+    /// Corner of the glyph, `(0, 0)` is top left and `(1, 1)` bottom right
+    corner: [f32; 2],
+}
+
+const QUAD_VERTICES: &[QuadVertex] = &[
+    QuadVertex { corner: [0.0, 0.0] },
+    QuadVertex { corner: [0.0, 1.0] },
+    QuadVertex { corner: [1.0, 1.0] },
+    QuadVertex { corner: [1.0, 0.0] },
+];
+
+const QUAD_INDICES: &[u16] = &[
+    0, 1, 2,
+    0, 2, 3,
+];
+
+/// One glyph on screen, positioned in normalized device coordinates
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GlyphInstance {
+    origin: [f32; 2],
+    size: [f32; 2],
+    uv_min: [f32; 2],
+    uv_max: [f32; 2],
+    color: [f32; 4],
+}
+
+impl GlyphInstance {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<GlyphInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Draws screen space text from a built-in bitmap font.
+///
+/// Text is collected with `push_text` during the frame, uploaded with
+/// `prepare` and drawn on top of whatever is already in the render pass.
+pub struct TextRenderer {
+    pub render_pipeline: wgpu::RenderPipeline,
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub font_texture: texture::Texture,
+    pub font_bind_group: wgpu::BindGroup,
+    pub instance_buffer: wgpu::Buffer,
+    glyphs: Vec<GlyphInstance>,
+    screen_size: [f32; 2],
+}
+
+impl TextRenderer {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let font_texture = texture::Texture::from_image(
+            device,
+            queue,
+            &image::DynamicImage::ImageRgba8(font_atlas()),
+            Some("font atlas"),
+        ).unwrap();
+
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("font_bind_group_layout"),
+            });
+
+        let font_bind_group = device.create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                layout: &texture_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&font_texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&font_texture.sampler),
+                    }
+                ],
+                label: Some("font_bind_group"),
+            }
+        );
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Text Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("text.wgsl").into())
+        });
+
+        let render_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Text Pipeline Layout"),
+                bind_group_layouts: &[&texture_bind_group_layout],
+                push_constant_ranges: &[],
+            }
+        );
+        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Text Pipeline"),
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[QuadVertex::desc(), GlyphInstance::desc()],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                // Glyph quads are built in screen space with y pointing down, so
+                // their winding is flipped compared to the board
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+            cache: None
+        });
+
+        let vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Text vertex Buffer"),
+                contents: bytemuck::cast_slice(QUAD_VERTICES),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+        let index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Text index Buffer"),
+                contents: bytemuck::cast_slice(QUAD_INDICES),
+                usage: wgpu::BufferUsages::INDEX,
+            }
+        );
+        let instance_buffer = create_glyph_buffer(device, INITIAL_GLYPHS);
+
+        Self {
+            render_pipeline,
+            vertex_buffer,
+            index_buffer,
+            font_texture,
+            font_bind_group,
+            instance_buffer,
+            glyphs: Vec::new(),
+            screen_size: [width as f32, height as f32],
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.screen_size = [width as f32, height as f32];
+    }
+
+    /// Width in pixels that `text` takes up at `scale` screen pixels per font pixel
+    pub fn text_width(text: &str, scale: f32) -> f32 {
+        (text.chars().count() as u32 * CELL_WIDTH) as f32 * scale
+    }
+
+    pub fn line_height(scale: f32) -> f32 {
+        CELL_HEIGHT as f32 * scale
+    }
+
+    /// Forgets the text pushed for the previous frame
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+    }
+
+    /// Lays out `text` with its top left corner at `(x, y)` pixels from the
+    /// top left of the screen. Characters missing from the font are skipped.
+    pub fn push_text(&mut self, text: &str, x: f32, y: f32, scale: f32, color: [f32; 4]) {
+        let [width, height] = self.screen_size;
+        let glyph_size = [
+            GLYPH_WIDTH as f32 * scale / width * 2.0,
+            -(GLYPH_HEIGHT as f32 * scale / height * 2.0),
+        ];
+        for (i, c) in text.chars().enumerate() {
+            let Some((uv_min, uv_max)) = glyph_uv(c) else {
+                continue;
+            };
+            let px = x + (i as u32 * CELL_WIDTH) as f32 * scale;
+            self.glyphs.push(GlyphInstance {
+                origin: [px / width * 2.0 - 1.0, 1.0 - y / height * 2.0],
+                size: glyph_size,
+                uv_min,
+                uv_max,
+                color,
+            });
+        }
+    }
+
+    /// Fills a rectangle `size` pixels across from `position`, for dimming
+    /// or backing the text drawn after it
+    pub fn push_rect(&mut self, position: [f32; 2], size: [f32; 2], color: [f32; 4]) {
+        let [width, height] = self.screen_size;
+        let Some((uv_min, uv_max)) = glyph_uv('█') else {
+            return;
+        };
+        // Sampling only the middle of the solid glyph keeps the padding
+        // around it from bleeding in at the edges
+        let middle = [(uv_min[0] + uv_max[0]) / 2.0, (uv_min[1] + uv_max[1]) / 2.0];
+        self.glyphs.push(GlyphInstance {
+            origin: [position[0] / width * 2.0 - 1.0, 1.0 - position[1] / height * 2.0],
+            size: [size[0] / width * 2.0, -(size[1] / height * 2.0)],
+            uv_min: middle,
+            uv_max: middle,
+            color,
+        });
+    }
+
+    /// Like `push_text`, but centred horizontally on the screen
+    pub fn push_centered(&mut self, text: &str, y: f32, scale: f32, color: [f32; 4]) {
+        let x = (self.screen_size[0] - Self::text_width(text, scale)) / 2.0;
+        self.push_text(text, x, y, scale, color);
+    }
+
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if self.instance_buffer.size() < (self.glyphs.len() * std::mem::size_of::<GlyphInstance>()) as u64 {
+            self.instance_buffer = create_glyph_buffer(device, self.glyphs.len().next_power_of_two());
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&self.glyphs));
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.glyphs.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, &self.font_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        render_pass.draw_indexed(0..QUAD_INDICES.len() as u32, 0, 0..self.glyphs.len() as _);
+    }
+}
+
+fn create_glyph_buffer(device: &wgpu::Device, glyphs: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Text instance Buffer"),
+        size: (glyphs * std::mem::size_of::<GlyphInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+impl QuadVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<QuadVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                }
+            ]
+        }
+    }
+}
+
+fn atlas_size() -> (u32, u32) {
+    let rows = (FONT.len() as u32).div_ceil(ATLAS_COLUMNS);
+    (ATLAS_COLUMNS * CELL_WIDTH, rows * CELL_HEIGHT)
+}
+
+/// Rasterizes `FONT` into white glyphs on a transparent background
+fn font_atlas() -> image::RgbaImage {
+    let (width, height) = atlas_size();
+    let mut atlas = image::RgbaImage::new(width, height);
+    for (i, (_, rows)) in FONT.iter().enumerate() {
+        let cell_x = (i as u32 % ATLAS_COLUMNS) * CELL_WIDTH;
+        let cell_y = (i as u32 / ATLAS_COLUMNS) * CELL_HEIGHT;
+        for (y, row) in rows.iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row & (0x10 >> x) != 0 {
+                    atlas.put_pixel(cell_x + x, cell_y + y as u32, image::Rgba([255, 255, 255, 255]));
+                }
+            }
+        }
+    }
+    atlas
+}
+
+fn glyph_uv(c: char) -> Option<([f32; 2], [f32; 2])> {
+    let c = c.to_ascii_uppercase();
+    let index = FONT.iter().position(|(glyph, _)| *glyph == c)? as u32;
+    let (width, height) = atlas_size();
+    let x = (index % ATLAS_COLUMNS * CELL_WIDTH) as f32;
+    let y = (index / ATLAS_COLUMNS * CELL_HEIGHT) as f32;
+    Some((
+        [x / width as f32, y / height as f32],
+        [(x + GLYPH_WIDTH as f32) / width as f32, (y + GLYPH_HEIGHT as f32) / height as f32],
+    ))
+}
@@ -0,0 +1,42 @@
+struct VertexInput {
+    @location(0) corner: vec2<f32>,
+};
+
+struct GlyphInput {
+    @location(1) origin: vec2<f32>,
+    @location(2) size: vec2<f32>,
+    @location(3) uv_min: vec2<f32>,
+    @location(4) uv_max: vec2<f32>,
+    @location(5) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    glyph: GlyphInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = mix(glyph.uv_min, glyph.uv_max, model.corner);
+    out.color = glyph.color;
+    out.clip_position = vec4<f32>(glyph.origin + model.corner * glyph.size, 0.0, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+@group(0) @binding(0)
+var t_font: texture_2d<f32>;
+@group(0) @binding(1)
+var s_font: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coverage = textureSample(t_font, s_font, in.tex_coords).a;
+    return vec4<f32>(in.color.rgb, in.color.a * coverage);
+}
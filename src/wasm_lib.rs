@@ -1,5 +1,7 @@
 
-use wasm_bindgen::prelude::*;
+use std::{cell::Cell, rc::Rc};
+
+use wasm_bindgen::{prelude::*, JsCast};
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
@@ -9,22 +11,86 @@ use winit::{
 use winit::platform::web::WindowExtWebSys;
 use winit::dpi::PhysicalSize;
 
-use crate::r#struct::State;
+use crate::{config::GameConfig, net::{Connection, DEFAULT_WEBSOCKET_PORT}, r#struct::State};
 use winit::dpi::LogicalSize;
 
+/// Reads the game config from a `<script id="snake-config" type="application/json">`
+/// element on the page, falling back to the defaults if there is none
+fn page_config() -> GameConfig {
+    let json = web_sys::window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("snake-config"))
+        .and_then(|element| element.text_content());
+    match json.map(|json| GameConfig::from_json(&json)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            log::error!("Ignoring snake-config: {e:#}");
+            GameConfig::default()
+        },
+        None => GameConfig::default(),
+    }
+}
+
+/// Connects to the server in the page's `?server=host[:port]&name=...` query,
+/// if there is one. The port defaults to `DEFAULT_WEBSOCKET_PORT`.
+fn page_connection() -> Option<Connection> {
+    let search = web_sys::window()?.location().search().ok()?;
+    let params = web_sys::UrlSearchParams::new_with_str(&search).ok()?;
+    let mut server = params.get("server")?;
+    if !server.contains("://") {
+        if !server.contains(':') {
+            server = format!("{server}:{DEFAULT_WEBSOCKET_PORT}");
+        }
+        server = format!("ws://{server}");
+    }
+    let name = params.get("name").unwrap_or_else(|| "player".to_string());
+    match Connection::connect(&server, &name) {
+        Ok(connection) => Some(connection),
+        Err(e) => {
+            log::error!("{e:#}");
+            None
+        },
+    }
+}
+
+/// Set whenever the page is hidden, such as by switching tabs. The browser
+/// stops drawing frames then, so the game checks it on the next one.
+fn watch_visibility() -> Rc<Cell<bool>> {
+    let hidden = Rc::new(Cell::new(false));
+    if let Some(document) = web_sys::window().and_then(|win| win.document()) {
+        let flag = hidden.clone();
+        let page = document.clone();
+        let on_change = Closure::<dyn FnMut()>::new(move || {
+            if page.hidden() {
+                flag.set(true);
+            }
+        });
+        match document.add_event_listener_with_callback("visibilitychange", on_change.as_ref().unchecked_ref()) {
+            Ok(()) => on_change.forget(),
+            Err(e) => log::error!("Couldn't watch page visibility: {e:?}"),
+        }
+    }
+    hidden
+}
+
 #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
 pub async fn run() {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
     console_log::init_with_level(log::Level::Warn).expect("Couldn't initialize logger");
+    let config = page_config();
     
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new()
     .with_title("Snake")
-    .with_inner_size(LogicalSize::new(800.0, 800.0))
+    .with_inner_size(LogicalSize::new(config.window_width, config.window_height))
     .with_resizable(false)
     .build(&event_loop).unwrap();
     window.set_resizable(false);
-    let mut state = State::new(&window).await;
+    let mut state = State::new(&window, config).await;
+    if let Some(connection) = page_connection() {
+        state.connect(connection);
+    }
+    let hidden = watch_visibility();
     
     web_sys::window()
         .and_then(|win| win.document())
@@ -36,13 +102,14 @@ pub async fn run() {
         })
         .expect("Couldn't append canvas to document body.");
 
+    let window = &window;
     event_loop.run(move |event, control_flow| {
         match event {
             
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == state.window().id() => if !state.input(event) {
+            } if window_id == window.id() => if !state.input(event) {
                 match event {
                 WindowEvent::CloseRequested
                 | WindowEvent::KeyboardInput {
@@ -55,8 +122,10 @@ pub async fn run() {
                     ..
                 } => control_flow.exit(),
                 WindowEvent::RedrawRequested => {
-                    state.window().request_redraw();
-                    
+                    window.request_redraw();
+                    if hidden.take() {
+                        state.pause();
+                    }
         
                     state.update();
                     match state.render() {
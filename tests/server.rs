@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+use rendering::{
+    config::GameConfig,
+    net::{Connection, Signal, Update},
+    server::Server,
+    snake::Edges,
+    SnakeInputs,
+};
+
+/// Polls `connection` until an update matches `done`, returning everything
+/// received up to and including it
+fn updates_until(connection: &mut Connection, done: impl Fn(&Update) -> bool) -> Vec<Update> {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut updates = Vec::new();
+    while Instant::now() < deadline {
+        for update in connection.poll().unwrap() {
+            let finished = done(&update);
+            updates.push(update);
+            if finished {
+                return updates;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    panic!("Timed out, got {updates:?}");
+}
+
+#[test]
+fn two_clients_play_a_game_on_localhost() {
+    let config = GameConfig { players: 2, edges: Edges::Solid, tick_ms: 20, seed: Some(1), ..GameConfig::default() };
+    let server = Server::bind("127.0.0.1:0", config).unwrap();
+    let address = server.local_addr();
+    std::thread::spawn(move || server.run());
+
+    let mut first = Connection::connect(address, "first").unwrap();
+    let lobby = updates_until(&mut first, |update| matches!(update, Update::Waiting { .. }));
+    assert!(matches!(lobby[..], [Update::Welcome { player: Some(0), .. }, Update::Waiting { joined: 1, needed: 2 }]));
+
+    let mut second = Connection::connect(address, "second").unwrap();
+    let joined = updates_until(&mut second, |update| matches!(update, Update::Snapshot(_)));
+    assert!(matches!(joined[..], [Update::Welcome { player: Some(1), .. }, Update::Snapshot(_)]));
+    let start = updates_until(&mut first, |update| matches!(update, Update::Snapshot(_)));
+    let Some(Update::Snapshot(start)) = start.last() else {
+        unreachable!()
+    };
+
+    // The first player turns, the second sees it
+    first.send(&Signal::Input { direction: SnakeInputs::Up }).unwrap();
+    let spawn = start.snakes[0].body[0];
+    updates_until(&mut second, |update| {
+        matches!(update, Update::Snapshot(snapshot) if snapshot.snakes[0].body[0].y > spawn.y)
+    });
+
+    // Nobody else steers, so the second snake runs into the edge first
+    for connection in [&mut first, &mut second] {
+        let end = updates_until(connection, |update| matches!(update, Update::GameOver { .. }));
+        assert!(matches!(end.last(), Some(Update::GameOver { winner: Some(0), .. })));
+    }
+}